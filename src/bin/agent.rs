@@ -5,8 +5,8 @@ use core::cli::{Cli, Commands, agent::Agent};
 async fn main() {
     let cli = Cli::parse();
     match cli.command {
-        Some(Commands::Agent { port, listen }) => {
-            let agent = Agent::new(port, listen);
+        Some(Commands::Agent { port, listen, host, dynamic, unix, socket }) => {
+            let agent = Agent::new(port, listen, host, dynamic, unix, socket.socket_mode);
             agent.exec().await
         },
         _ => {},
@@ -1,6 +1,8 @@
-use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::{fmt, net::{SocketAddr, Ipv6Addr}, time::Duration};
 
-use clap::{Parser, Subcommand, AppSettings, ValueEnum};
+use clap::{Parser, Subcommand, AppSettings, ValueEnum, Args};
+
+use crate::endpoint::{inspect::View, shaping::Shaping, socket::localhost};
 
 
 
@@ -21,9 +23,23 @@ static FORWARD_POINT_HELP: &str =
 "
 Available forward points are:
     Kubernetes: '<context>/<namespace>/<pod>:<PORT>'
+    Kubernetes jump host: '<context>/<namespace>/<pod>:<HOST>:<PORT>'
+    Kubernetes relay pod: '<context>/<namespace>/:<HOST>:<PORT>'
     Docker: '<container>:<PORT>'
-    Local: '[ADDR]:<PORT>'
+    Local: '[ADDR]:<PORT>', IPv6 in brackets: '[::1]:<PORT>'
+    Kubernetes unix socket: '<context>/<namespace>/<pod>:unix:<PATH>'
+    Docker unix socket: '<container>:unix:<PATH>'
+    Local unix socket: 'unix:<PATH>'
+    Kubernetes process: '<context>/<namespace>/<pod>:exec:<COMMAND>'
+    Docker process: '<container>:exec:<COMMAND>'
+    Local process: 'exec:<COMMAND>'
     STDIO: '-'
+
+TLS is terminated on ORIGIN and originated to DESTINATION prefixed with 'tls+':
+    'tls+:8443', 'tls+<context>/<namespace>/<pod>:443'
+
+Consecutive TCP ports are forwarded with ranges of the same length on both ends:
+    ':9092-9094', '<context>/<namespace>/<pod>:9092-9094'
 ";
 
 static COPY_POINT_HELP: &str = 
@@ -34,11 +50,26 @@ Available copy points are:
     Local: '<PATH>'
 ";
 
+static AGENT_POINT_HELP: &str = 
+"
+Available targets are:
+    Kubernetes: '<context>/<namespace>/<pod>'
+    Kubernetes relay pod: '<context>/<namespace>/'
+    Docker: '<container>'
+";
+
 #[derive(Debug, Clone, Copy, ValueEnum)]
 pub enum Shell {
     Bash
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    Text,
+    // events as JSON lines
+    Json
+}
+
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
@@ -58,19 +89,96 @@ pub enum Commands {
         /// Destination
         #[clap(value_parser=str_to_copy_point, name="DESTINATION", long_help=COPY_POINT_HELP)]
         dst: CopyPoint,
+
+        /// Maximum copy rate, e.g. 5MB/s or 512KiB/s
+        #[clap(long, value_parser=str_to_byte_rate, value_name="RATE")]
+        limit: Option<u64>,
     },
     
     /// Port forward from ORIGIN to DESTINATION
-    Pf {
-        /// Originating endpoint
-        #[clap(value_parser=str_to_forward_point, name="ORIGIN", long_help=FORWARD_POINT_HELP)]
-        origin: ForwardPoint,
-
-        /// Destination endpoint
-        #[clap(value_parser=str_to_forward_point, name="DESTINATION", long_help=FORWARD_POINT_HELP)]
-        dst: ForwardPoint,
-    },
+    Pf(Box<PfArgs>),
     
+    /// Run SOCKS5 proxy on LISTEN connecting through TARGET
+    ///
+    /// At most 254 connections are open through the agent at a time, further ones wait until one closes
+    Socks {
+        /// Local address of the proxy
+        #[clap(value_parser=str_to_local_addrs, name="LISTEN")]
+        listen: LocalAddrs,
+
+        /// Pod or container connections are made from
+        #[clap(value_parser=str_to_agent_point, name="TARGET", long_help=AGENT_POINT_HELP)]
+        target: AgentPoint,
+
+        #[clap(flatten)]
+        relay: RelayArgs,
+
+        #[clap(flatten)]
+        ephemeral: EphemeralArgs,
+
+        #[clap(flatten)]
+        sidecar: SidecarArgs,
+
+        #[clap(flatten)]
+        agent: AgentArgs,
+    },
+
+    /// Run HTTP proxy on LISTEN connecting through TARGET
+    ///
+    /// At most 254 connections are open through the agent at a time, further ones wait until one closes
+    Proxy {
+        /// Local address of the proxy
+        #[clap(value_parser=str_to_local_addrs, name="LISTEN")]
+        listen: LocalAddrs,
+
+        /// Pod or container connections are made from
+        #[clap(value_parser=str_to_agent_point, name="TARGET", long_help=AGENT_POINT_HELP)]
+        target: AgentPoint,
+
+        #[clap(flatten)]
+        relay: RelayArgs,
+
+        #[clap(flatten)]
+        ephemeral: EphemeralArgs,
+
+        #[clap(flatten)]
+        sidecar: SidecarArgs,
+
+        #[clap(flatten)]
+        agent: AgentArgs,
+    },
+
+    /// Forward SERVICES through TARGET on loopback addresses allocated for their names
+    Services {
+        /// Pod or container connections are made from
+        #[clap(value_parser=str_to_agent_point, name="TARGET", long_help=AGENT_POINT_HELP)]
+        target: AgentPoint,
+
+        /// Services to forward, names resolve through the agent
+        #[clap(value_parser=str_to_service, name="HOST:PORT", required=true)]
+        services: Vec<(String, u16)>,
+
+        /// Run DNS server answering for forwarded names on this address
+        #[clap(long, value_parser=str_to_local_addr, value_name="ADDR")]
+        dns: Option<SocketAddr>,
+
+        /// Add forwarded names to /etc/hosts until the session ends
+        #[clap(long, value_parser)]
+        hosts: bool,
+
+        #[clap(flatten)]
+        relay: RelayArgs,
+
+        #[clap(flatten)]
+        ephemeral: EphemeralArgs,
+
+        #[clap(flatten)]
+        sidecar: SidecarArgs,
+
+        #[clap(flatten)]
+        agent: AgentArgs,
+    },
+
     /// Output shell completion code
     Completion {
         /// Shell 
@@ -85,13 +193,210 @@ pub enum Commands {
         #[clap(short='l', long, value_parser)]
         listen: bool,
 
-        /// Local port number
-        #[clap(short='p', long, value_parser)]
-        port: u16
+        /// Local port number, repeated to listen on every port of a range
+        #[clap(short='p', long, value_parser, required_unless_present_any=&["dynamic", "unix"])]
+        port: Vec<u16>,
+
+        /// Connect to destinations requested by `rs`
+        #[clap(long, value_parser, conflicts_with_all=&["listen", "port", "host", "unix"])]
+        dynamic: bool,
+
+        /// Unix socket to listen on or connect to instead of a port
+        #[clap(long, value_parser, conflicts_with_all=&["port", "host"])]
+        unix: Option<String>,
+
+        #[clap(flatten)]
+        socket: SocketArgs,
+
+        /// Remote host to connect to instead of localhost
+        #[clap(long, value_parser)]
+        host: Option<String>,
     },
 
 }
 
+#[derive(Args, Debug)]
+pub struct PfArgs {
+    /// Originating endpoint
+    #[clap(value_parser=str_to_forward_range, name="ORIGIN", long_help=FORWARD_POINT_HELP)]
+    pub origin: ForwardRange,
+
+    /// Destination endpoint
+    #[clap(value_parser=str_to_forward_range, name="DESTINATION", long_help=FORWARD_POINT_HELP)]
+    pub dst: ForwardRange,
+
+    #[clap(flatten)]
+    pub relay: RelayArgs,
+
+    #[clap(flatten)]
+    pub ephemeral: EphemeralArgs,
+
+    #[clap(flatten)]
+    pub sidecar: SidecarArgs,
+
+    #[clap(flatten)]
+    pub agent: AgentArgs,
+
+    #[clap(flatten)]
+    pub socket: SocketArgs,
+
+    #[clap(flatten)]
+    pub tls: TlsArgs,
+
+    #[clap(flatten)]
+    pub traffic: TrafficArgs,
+
+    #[clap(flatten)]
+    pub limits: LimitArgs,
+
+    /// Format of the line printed when ORIGIN accepts connections
+    #[clap(long, value_parser, arg_enum, default_value="text", value_name="FORMAT")]
+    pub output: Output,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct RelayArgs {
+    /// Image of the relay pod
+    #[clap(long, value_parser, default_value="busybox:stable")]
+    pub relay_image: String,
+
+    /// Node selector of the relay pod, can be repeated
+    #[clap(long, value_parser=str_to_key_value, value_name="KEY=VALUE")]
+    pub relay_node_selector: Vec<(String, String)>,
+
+    /// Time the relay pod has to start in, including the image pull
+    #[clap(long, value_parser=str_to_duration, default_value="5m", value_name="DURATION")]
+    pub relay_timeout: Duration,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct EphemeralArgs {
+    /// Run agent in an ephemeral container added to the pod
+    #[clap(long, value_parser)]
+    pub ephemeral: bool,
+
+    /// Image of the ephemeral container
+    #[clap(long, value_parser, default_value="busybox:stable")]
+    pub ephemeral_image: String,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SidecarArgs {
+    /// Image of the container started next to containers the agent could not be installed into
+    #[clap(long, value_parser, default_value="busybox:stable")]
+    pub sidecar_image: String,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SocketArgs {
+    /// Mode of unix sockets listened on, e.g. 660
+    #[clap(long, value_parser=str_to_mode, value_name="MODE")]
+    pub socket_mode: Option<u32>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TlsArgs {
+    /// Certificate chain presented on tls+ ORIGIN
+    #[clap(long, value_parser, value_name="PEM")]
+    pub tls_cert: Option<String>,
+
+    /// Private key of --tls-cert
+    #[clap(long, value_parser, value_name="PEM")]
+    pub tls_key: Option<String>,
+
+    /// CA certificates verifying tls+ DESTINATION instead of system ones
+    #[clap(long, value_parser, value_name="PEM")]
+    pub tls_ca: Option<String>,
+
+    /// Server name sent as SNI and verified on tls+ DESTINATION, defaults to its host
+    #[clap(long, value_parser, value_name="NAME")]
+    pub tls_sni: Option<String>,
+
+    /// Do not verify certificate of tls+ DESTINATION
+    #[clap(long, value_parser)]
+    pub tls_insecure: bool,
+
+    /// Verify only the certificate chain of a tls+ pod or container DESTINATION without --tls-sni
+    #[clap(long, value_parser)]
+    pub tls_insecure_hostname: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TrafficArgs {
+    /// Print traffic of each connection on stderr as hex, text or http request and response lines
+    #[clap(long, value_parser=str_to_inspect_view, value_name="VIEW")]
+    pub inspect: Option<View>,
+
+    /// Record connections to the file as TCP flows, e.g. for Wireshark
+    #[clap(long, value_parser, value_name="FILE")]
+    pub pcap: Option<String>,
+
+    /// Delay added to data in each direction, e.g. 200ms
+    #[clap(long, value_parser=str_to_duration, value_name="DURATION")]
+    pub latency: Option<Duration>,
+
+    /// Random variation of --latency either way
+    #[clap(long, value_parser=str_to_duration, value_name="DURATION")]
+    pub jitter: Option<Duration>,
+
+    /// Bandwidth of each direction, e.g. 1mbit or 100kbps
+    #[clap(long, value_parser=str_to_bandwidth, value_name="RATE")]
+    pub bandwidth: Option<u64>,
+
+    /// Probability of dropping the connection on each chunk of data read, e.g. 0.01
+    #[clap(long, value_parser=str_to_probability, value_name="P", default_value="0")]
+    pub drop_rate: f64,
+
+    /// Probability of stalling delivery on each chunk of data read
+    #[clap(long, value_parser=str_to_probability, value_name="P", default_value="0")]
+    pub stall_rate: f64,
+
+    /// How long stalls last
+    #[clap(long, value_parser=str_to_duration, value_name="DURATION", default_value="1s")]
+    pub stall: Duration,
+}
+
+impl TrafficArgs {
+    // Faults added to forwarded connections, None if there are none
+    pub fn shaping(&self) -> Option<Shaping> {
+        let shaping = Shaping {
+            latency: self.latency.unwrap_or_default(),
+            jitter: self.jitter.unwrap_or_default(),
+            bandwidth: self.bandwidth,
+            drop_rate: self.drop_rate,
+            stall_rate: self.stall_rate,
+            stall: self.stall
+        };
+        Some(shaping).filter(Shaping::is_active)
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct LimitArgs {
+    /// Connections forwarded at once, others wait until one of them closes
+    #[clap(long, value_parser=clap::value_parser!(u32).range(1..), value_name="N")]
+    pub max_connections: Option<u32>,
+
+    /// Close connections nothing was sent over for this long, e.g. 5m
+    #[clap(long, value_parser=str_to_duration, value_name="DURATION")]
+    pub idle_timeout: Option<Duration>,
+
+    /// Close connections open for this long, e.g. 1h
+    #[clap(long, value_parser=str_to_duration, value_name="DURATION")]
+    pub max_lifetime: Option<Duration>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct AgentArgs {
+    /// Directory the agent is installed into before falling back to /tmp, /dev/shm, emptyDir mounts and $HOME
+    #[clap(long, value_parser)]
+    pub agent_dir: Option<String>,
+
+    /// Run the agent from memory with python3 instead of installing it
+    #[clap(long, value_parser)]
+    pub agent_memfd: bool,
+}
+
 #[derive(Debug, Clone)]
 pub enum CopyPoint {
     Docker(DockerCopyPoint),
@@ -116,21 +421,157 @@ pub struct DockerCopyPoint {
 pub enum ForwardPoint {
     Docker(DockerForwardPoint),
     Kube(KubeForwardPoint),
-    Local(SocketAddr),
+    // `localhost` stands for the loopback addresses of both families
+    Local(Vec<SocketAddr>),
+    Unix(String),
+    // command started with `sh -c` for each connection
+    Exec(String),
     Stdio,
+    // TLS terminated on origins and originated to destinations
+    Tls(Box<ForwardPoint>),
+}
+
+// Same syntax the forward point is parsed from
+impl fmt::Display for ForwardPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardPoint::Kube(KubeForwardPoint{context, namespace, pod, host, port, ..}) => {
+                write!(f, "{}/{}/{}:", context, namespace, pod.as_deref().unwrap_or_default())?;
+                match host {
+                    Some(host) if host.contains(':') => write!(f, "[{}]:", host)?,
+                    Some(host) => write!(f, "{}:", host)?,
+                    None => {},
+                }
+                write!(f, "{}", port)
+            },
+            ForwardPoint::Docker(DockerForwardPoint{container, port}) => write!(f, "{}:{}", container, port),
+            ForwardPoint::Local(addrs) => match addrs.as_slice() {
+                [addr] => write!(f, "{}", addr),
+                addrs => write!(f, "localhost:{}", addrs.first().map(|a| a.port()).unwrap_or_default()),
+            },
+            ForwardPoint::Unix(path) => write!(f, "unix:{}", path),
+            ForwardPoint::Exec(command) => write!(f, "exec:{}", command),
+            ForwardPoint::Stdio => write!(f, "-"),
+            ForwardPoint::Tls(p) => write!(f, "tls+{}", p),
+        }
+    }
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Port::Tcp(port) => write!(f, "{}", port),
+            Port::Unix(path) => write!(f, "unix:{}", path),
+            Port::Exec(command) => write!(f, "exec:{}", command),
+        }
+    }
+}
+
+impl ForwardPoint {
+    // Forward point TLS is added to
+    pub fn without_tls(&self) -> &ForwardPoint {
+        match self {
+            ForwardPoint::Tls(p) => p,
+            p => p,
+        }
+    }
+
+    // None for unix sockets, processes and STDIO
+    pub fn tcp_port(&self) -> Option<u16> {
+        match self {
+            ForwardPoint::Kube(KubeForwardPoint{port: Port::Tcp(port), ..}) | ForwardPoint::Docker(DockerForwardPoint{port: Port::Tcp(port), ..}) => Some(*port),
+            ForwardPoint::Local(addrs) => addrs.first().map(|a| a.port()),
+            ForwardPoint::Tls(p) => p.tcp_port(),
+            _ => None,
+        }
+    }
+
+    // Same forward point with another TCP port
+    pub fn with_tcp_port(&self, port: u16) -> ForwardPoint {
+        match self {
+            ForwardPoint::Kube(k) if matches!(k.port, Port::Tcp(_)) => ForwardPoint::Kube(KubeForwardPoint{port: Port::Tcp(port), ..k.clone()}),
+            ForwardPoint::Docker(d) if matches!(d.port, Port::Tcp(_)) => ForwardPoint::Docker(DockerForwardPoint{port: Port::Tcp(port), ..d.clone()}),
+            ForwardPoint::Local(addrs) => ForwardPoint::Local(addrs.iter().map(|a| SocketAddr::new(a.ip(), port)).collect()),
+            ForwardPoint::Tls(p) => ForwardPoint::Tls(Box::new(p.with_tcp_port(port))),
+            p => p.clone(),
+        }
+    }
+}
+
+// Forward point of consecutive TCP ports starting with its own one
+#[derive(Debug, Clone)]
+pub struct ForwardRange {
+    pub point: ForwardPoint,
+    // 1 for forward points without a range
+    pub ports: u16
+}
+
+impl ForwardRange {
+    // Forward point of each port in the range
+    pub fn points(&self) -> Vec<ForwardPoint> {
+        match self.point.tcp_port() {
+            Some(first) if self.ports > 1 => (0..self.ports).map(|i| self.point.with_tcp_port(first + i)).collect(),
+            _ => vec![self.point.clone()],
+        }
+    }
+}
+
+impl fmt::Display for ForwardRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.point.tcp_port() {
+            Some(first) if self.ports > 1 => write!(f, "{}-{}", self.point, first + self.ports - 1),
+            _ => write!(f, "{}", self.point),
+        }
+    }
+}
+
+// Port, unix socket or process in a pod or container
+#[derive(Debug, Clone)]
+pub enum Port {
+    Tcp(u16),
+    Unix(String),
+    Exec(String),
+}
+
+impl Port {
+    // Arguments telling the agent what to listen on or connect to
+    pub fn agent_args(&self) -> Vec<String> {
+        match self {
+            Port::Tcp(port) => vec!["-p".to_string(), port.to_string()],
+            Port::Unix(path) => vec!["--unix".to_string(), path.clone()],
+            Port::Exec(_) => unreachable!("processes are started without the agent"),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct KubeForwardPoint {
     pub context: String,
     pub namespace: String,
-    pub pod: String,
-    pub port: u16
+    // None means relay pod created for the session
+    pub pod: Option<String>,
+    // Some means the pod is used as a jump host
+    pub host: Option<String>,
+    // container running the agent, default one if None
+    pub container: Option<String>,
+    pub port: Port
 }
 #[derive(Debug, Clone)]
 pub struct DockerForwardPoint {
     pub container: String,
-    pub port: u16,
+    pub port: Port,
+}
+
+// Pod or container running the agent which connects to destinations chosen at runtime
+#[derive(Debug, Clone)]
+pub enum AgentPoint {
+    Docker(String),
+    Kube {
+        context: String,
+        namespace: String,
+        // None means relay pod created for the session
+        pod: Option<String>,
+    },
 }
 
 fn str_to_ls_path(val: &str) -> Result<String, String> {
@@ -138,53 +579,119 @@ fn str_to_ls_path(val: &str) -> Result<String, String> {
     if !val.contains(":") && !val.ends_with("/") {
         return Err(err);
     }
-    return Ok(val.to_string());
+    Ok(val.to_string())
+}
+
+// Ranges replace the TCP port, e.g. ':9092-9094', commands and unix socket paths are not ranges
+fn str_to_forward_range(val: &str) -> Result<ForwardRange, String> {
+    let range = val.rsplit_once(':').and_then(|(point, ports)| {
+        let (first, last) = ports.split_once('-')?;
+        Some((point, first.parse::<u16>().ok()?, last.parse::<u16>().ok()?))
+    });
+    if let Some((point, first, last)) = range {
+        if let Ok(point) = str_to_forward_point(&format!("{}:{}", point, first)) {
+            if point.tcp_port() == Some(first) {
+                if last < first || first == 0 {
+                    return Err("Invalid port range".to_string());
+                }
+                return Ok(ForwardRange { point, ports: last - first + 1 });
+            }
+        }
+    }
+    Ok(ForwardRange { point: str_to_forward_point(val)?, ports: 1 })
 }
 
 fn str_to_forward_point(val: &str) -> Result<ForwardPoint, String> {
+    if let Some(point) = val.strip_prefix("tls+") {
+        return match str_to_forward_point(point)? {
+            ForwardPoint::Tls(_) => Err("TLS could only be added once".to_string()),
+            point => Ok(ForwardPoint::Tls(Box::new(point))),
+        };
+    }
+    // commands may contain anything, so they are matched first
+    if let Some(command) = val.strip_prefix("exec:") {
+        return match command.trim().is_empty() {
+            true => Err("Missing <COMMAND>".to_string()),
+            false => Ok(ForwardPoint::Exec(command.to_string())),
+        };
+    }
+    if let Some((point, command)) = val.split_once(":exec:") {
+        if command.trim().is_empty() {
+            return Err("Missing <COMMAND>".to_string());
+        }
+        return str_to_remote_forward_point(point, Port::Exec(command.to_string()));
+    }
+    if let Some(path) = val.strip_prefix("unix:") {
+        return match path.is_empty() {
+            true => Err("Missing unix socket <PATH>".to_string()),
+            false => Ok(ForwardPoint::Unix(path.to_string())),
+        };
+    }
+    if let Some((point, path)) = val.split_once(":unix:") {
+        if path.is_empty() {
+            return Err("Missing unix socket <PATH>".to_string());
+        }
+        return str_to_remote_forward_point(point, Port::Unix(path.to_string()));
+    }
     let parts: Vec<String> = val.split("/").map(|p| {String::from(p)}).collect();
     match parts.len() {
         1 => {
             if parts[0] == "-" {
                 return Ok(ForwardPoint::Stdio);
             }
+            // bracketed IPv6 address
+            if parts[0].starts_with("[") {
+                let addr = parts[0].parse::<SocketAddr>().or(Err("Invalid address"))?;
+                return Ok(ForwardPoint::Local(vec![addr]));
+            }
             if parts[0].contains(":") {
                 let host_and_port: Vec<String> = parts[0].split(":").map(|p| {String::from(p)}).collect();
-                let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
                 let port = host_and_port[1].parse::<u16>().or(Err("Invalid port"))?;
-                if host_and_port[0].is_empty() {
-                    return Ok(ForwardPoint::Local(
-                        SocketAddr::new(ip, port)
-                    ));
-                }
-                
-                if host_and_port[0].eq("localhost") || host_and_port[0].eq("127.0.0.1") {
-                    return Ok(ForwardPoint::Local(
-                        SocketAddr::new(ip, port)
-                    ));
+                // only `localhost` is dual-stack, explicit addresses are used as they are
+                if host_and_port[0].is_empty() || host_and_port[0].eq("localhost") {
+                    return Ok(ForwardPoint::Local(localhost(port)));
                 }
                 if host_and_port[0].matches(".").count() == 3 {
                     let addr = parts[0].parse::<SocketAddr>().or(Err("Invalid address"))?;
-                    return Ok(ForwardPoint::Local(addr));
+                    return Ok(ForwardPoint::Local(vec![addr]));
                 }
-                return Ok(ForwardPoint::Docker(DockerForwardPoint{container: host_and_port[0].clone(), port }));
+                return Ok(ForwardPoint::Docker(DockerForwardPoint{container: host_and_port[0].clone(), port: Port::Tcp(port) }));
             }
             Err("Missing :<PORT> part".to_string())
         },
         3 => {
-            if parts[2].contains(":") {
-                let pod_port: Vec<String> = parts[2].split(":").map(|p| {String::from(p)}).collect();
-                return Ok(ForwardPoint::Kube(
-                    KubeForwardPoint {
-                        context: parts[0].clone(),
-                        namespace: parts[1].clone(),
-                        pod: pod_port[0].clone(),
-                        port: pod_port[1].parse::<u16>().unwrap(),
+            let (pod, host, port) = match parts[2].split_once(":[") {
+                // bracketed IPv6 jump host
+                Some((pod, host_port)) => {
+                    let (host, port) = host_port.split_once("]:").ok_or("Missing :<PORT> part")?;
+                    host.parse::<Ipv6Addr>().or(Err("Invalid address"))?;
+                    (pod.to_string(), Some(host.to_string()), port.to_string())
+                },
+                None if parts[2].contains(":") => {
+                    let pod_port: Vec<String> = parts[2].split(":").map(|p| {String::from(p)}).collect();
+                    match pod_port.len() {
+                        2 => (pod_port[0].clone(), None, pod_port[1].clone()),
+                        3 if !pod_port[1].is_empty() => (pod_port[0].clone(), Some(pod_port[1].clone()), pod_port[2].clone()),
+                        _ => return Err("Not a valid forward path".to_string()),
                     }
-                ));
-            }
-            
-            Err("Missing :<PORT> part".to_string())
+                },
+                None => return Err("Missing :<PORT> part".to_string()),
+            };
+            let pod = match pod.is_empty() {
+                true if host.is_none() => return Err("Relay pod requires <HOST>:<PORT> part".to_string()),
+                true => None,
+                false => Some(pod),
+            };
+            Ok(ForwardPoint::Kube(
+                KubeForwardPoint {
+                    context: parts[0].clone(),
+                    namespace: parts[1].clone(),
+                    pod,
+                    host,
+                    container: None,
+                    port: Port::Tcp(port.parse::<u16>().or(Err("Invalid port"))?),
+                }
+            ))
         },
         _ => {
             Err("Not a valid forward path".to_string())
@@ -192,6 +699,146 @@ fn str_to_forward_point(val: &str) -> Result<ForwardPoint, String> {
     }
 }
 
+// Unix sockets and processes in a pod or container
+fn str_to_remote_forward_point(point: &str, port: Port) -> Result<ForwardPoint, String> {
+    let parts: Vec<&str> = point.split("/").collect();
+    match parts.as_slice() {
+        [container] if !container.is_empty() => Ok(ForwardPoint::Docker(DockerForwardPoint{container: container.to_string(), port})),
+        [_, _, ""] => Err("Relay pod requires <HOST>:<PORT> part".to_string()),
+        [context, namespace, pod] => Ok(ForwardPoint::Kube(KubeForwardPoint {
+            context: context.to_string(),
+            namespace: namespace.to_string(),
+            pod: Some(pod.to_string()),
+            host: None,
+            container: None,
+            port,
+        })),
+        _ => Err("Not a valid forward path".to_string()),
+    }
+}
+
+// Addresses of one LISTEN argument, behind an alias so clap does not take the field for a repeated argument
+pub type LocalAddrs = Vec<SocketAddr>;
+
+fn str_to_local_addrs(val: &str) -> Result<LocalAddrs, String> {
+    match str_to_forward_point(val)? {
+        ForwardPoint::Local(addrs) => Ok(addrs),
+        _ => Err("must follow [ADDR]:<PORT>".to_string()),
+    }
+}
+
+// Single address, `localhost` is its IPv4 one
+fn str_to_local_addr(val: &str) -> Result<SocketAddr, String> {
+    str_to_local_addrs(val)?.first().copied().ok_or("must follow [ADDR]:<PORT>".to_string())
+}
+
+fn str_to_agent_point(val: &str) -> Result<AgentPoint, String> {
+    let parts: Vec<&str> = val.split("/").collect();
+    match parts.as_slice() {
+        [container] if !container.is_empty() && !container.contains(":") => Ok(AgentPoint::Docker(container.to_string())),
+        [context, namespace, pod] if !context.is_empty() && !namespace.is_empty() && !pod.contains(":") => Ok(AgentPoint::Kube {
+            context: context.to_string(),
+            namespace: namespace.to_string(),
+            pod: match pod.is_empty() {
+                true => None,
+                false => Some(pod.to_string()),
+            },
+        }),
+        _ => Err("must follow <context>/<namespace>/[pod] OR <container>".to_string()),
+    }
+}
+
+fn str_to_service(val: &str) -> Result<(String, u16), String> {
+    match val.rsplit_once(":") {
+        Some((host, port)) if !host.is_empty() && !host.contains("/") => Ok((host.to_string(), port.parse::<u16>().or(Err("Invalid port"))?)),
+        _ => Err("must follow <HOST>:<PORT>".to_string()),
+    }
+}
+
+fn str_to_mode(val: &str) -> Result<u32, String> {
+    u32::from_str_radix(val, 8).ok().filter(|m| *m <= 0o777).ok_or("must be an octal mode like 660".to_string())
+}
+
+fn str_to_inspect_view(val: &str) -> Result<View, String> {
+    match val {
+        "hex" => Ok(View::Hex),
+        "text" => Ok(View::Text),
+        "http" => Ok(View::Http),
+        _ => Err("must be one of hex, text or http".to_string()),
+    }
+}
+
+// Durations like 500us, 200ms, 1.5s, 2m or 1h
+fn str_to_duration(val: &str) -> Result<Duration, String> {
+    let err = || "must be a duration like 200ms, 1.5s, 2m or 1h".to_string();
+    let split = val.find(|c: char| c.is_ascii_alphabetic()).ok_or_else(err)?;
+    let value: f64 = val[..split].parse().map_err(|_| err())?;
+    let unit = match &val[split..] {
+        "us" => 0.000_001,
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(err()),
+    };
+    Duration::try_from_secs_f64(value * unit).map_err(|_| err())
+}
+
+// Rates in bytes per second, bit units are bits per second and bps units bytes per second like in tc
+fn str_to_bandwidth(val: &str) -> Result<u64, String> {
+    let err = || "must be a rate like 512kbit, 10mbit or 100kbps".to_string();
+    let lower = val.to_ascii_lowercase();
+    let split = lower.find(|c: char| c.is_ascii_alphabetic()).ok_or_else(err)?;
+    let value: f64 = lower[..split].parse().map_err(|_| err())?;
+    let unit = match &lower[split..] {
+        "bit" => 1.0 / 8.0,
+        "kbit" => 1_000.0 / 8.0,
+        "mbit" => 1_000_000.0 / 8.0,
+        "gbit" => 1_000_000_000.0 / 8.0,
+        "bps" => 1.0,
+        "kbps" => 1_000.0,
+        "mbps" => 1_000_000.0,
+        "gbps" => 1_000_000_000.0,
+        _ => return Err(err()),
+    };
+    match value * unit {
+        rate if rate >= 1.0 && rate.is_finite() => Ok(rate as u64),
+        _ => Err(err()),
+    }
+}
+
+// Bytes per second like 5MB/s, 512KiB/s or 1G
+fn str_to_byte_rate(val: &str) -> Result<u64, String> {
+    let err = || "must be a rate like 5MB/s or 512KiB/s".to_string();
+    let val = val.strip_suffix("/s").unwrap_or(val);
+    let split = val.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(val.len());
+    let value: f64 = val[..split].parse().map_err(|_| err())?;
+    let unit = match val[split..].to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1.0,
+        "K" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "KI" => 1024.0,
+        "MI" => 1024.0 * 1024.0,
+        "GI" => 1024.0 * 1024.0 * 1024.0,
+        _ => return Err(err()),
+    };
+    match value * unit {
+        rate if rate >= 1.0 && rate.is_finite() => Ok(rate as u64),
+        _ => Err(err()),
+    }
+}
+
+fn str_to_probability(val: &str) -> Result<f64, String> {
+    val.parse::<f64>().ok().filter(|p| (0.0..=1.0).contains(p)).ok_or("must be a probability between 0 and 1".to_string())
+}
+
+fn str_to_key_value(val: &str) -> Result<(String, String), String> {
+    match val.split_once("=") {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err("must follow <KEY>=<VALUE>".to_string()),
+    }
+}
 
 fn str_to_copy_point(val: &str) -> Result<CopyPoint, String> {
     
@@ -225,5 +872,127 @@ pub mod ls;
 pub mod pf;
 pub mod cp;
 pub mod agent;
+pub mod socks;
+pub mod proxy;
+pub mod services;
 pub mod complete;
-mod path_parser;
\ No newline at end of file
+mod path_parser;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_forward_points() {
+        assert!(matches!(str_to_forward_point("unix:/var/run/docker.sock"), Ok(ForwardPoint::Unix(p)) if p == "/var/run/docker.sock"));
+        assert!(matches!(
+            str_to_forward_point("db:unix:/run/postgresql/.s.PGSQL.5432"),
+            Ok(ForwardPoint::Docker(DockerForwardPoint{container, port: Port::Unix(p)})) if container == "db" && p == "/run/postgresql/.s.PGSQL.5432"
+        ));
+        assert!(matches!(
+            str_to_forward_point("ctx/ns/pod:unix:/tmp/grpc.sock"),
+            Ok(ForwardPoint::Kube(KubeForwardPoint{pod: Some(pod), host: None, port: Port::Unix(p), ..})) if pod == "pod" && p == "/tmp/grpc.sock"
+        ));
+        assert!(str_to_forward_point("ctx/ns/:unix:/tmp/grpc.sock").is_err());
+        assert!(str_to_forward_point("unix:").is_err());
+    }
+
+    #[test]
+    fn exec_forward_points() {
+        assert!(matches!(str_to_forward_point("exec:openssl s_client -connect db:5432"), Ok(ForwardPoint::Exec(c)) if c == "openssl s_client -connect db:5432"));
+        assert!(matches!(
+            str_to_forward_point("ctx/ns/pod:exec:psql -h /run/postgresql"),
+            Ok(ForwardPoint::Kube(KubeForwardPoint{pod: Some(_), port: Port::Exec(c), ..})) if c == "psql -h /run/postgresql"
+        ));
+        assert!(matches!(
+            str_to_forward_point("db:exec:socat - unix:/tmp/s"),
+            Ok(ForwardPoint::Docker(DockerForwardPoint{port: Port::Exec(c), ..})) if c == "socat - unix:/tmp/s"
+        ));
+        assert!(str_to_forward_point("exec: ").is_err());
+    }
+
+    #[test]
+    fn durations_and_rates() {
+        assert_eq!(str_to_duration("200ms"), Ok(Duration::from_millis(200)));
+        assert_eq!(str_to_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert!(str_to_duration("200").is_err());
+        assert!(str_to_duration("-1s").is_err());
+        assert_eq!(str_to_bandwidth("1mbit"), Ok(125_000));
+        assert_eq!(str_to_bandwidth("100KBPS"), Ok(100_000));
+        assert!(str_to_bandwidth("1kb").is_err());
+        assert!(str_to_probability("1.5").is_err());
+        assert_eq!(str_to_byte_rate("5MB/s"), Ok(5_000_000));
+        assert_eq!(str_to_byte_rate("512KiB/s"), Ok(512 * 1024));
+        assert_eq!(str_to_byte_rate("100"), Ok(100));
+        assert!(str_to_byte_rate("5Mbit/s").is_err());
+    }
+
+    #[test]
+    fn forward_points_display_as_parsed() {
+        for point in ["ctx/ns/pod:8080", "ctx/ns/:db.local:5432", "ctx/ns/pod:[fd00::1]:5432", "web:unix:/run/app.sock",
+                "tls+ctx/ns/pod:exec:nc localhost 80", "[::1]:0", "127.0.0.1:8080", "unix:/tmp/a.sock", "-"] {
+            assert_eq!(str_to_forward_point(point).unwrap().to_string(), point);
+        }
+    }
+
+    #[test]
+    fn forward_ranges() {
+        let range = str_to_forward_range("ctx/ns/pod:9092-9094").unwrap();
+        assert_eq!(range.ports, 3);
+        assert_eq!(range.to_string(), "ctx/ns/pod:9092-9094");
+        let points: Vec<String> = range.points().iter().map(|p| p.to_string()).collect();
+        assert_eq!(points, ["ctx/ns/pod:9092", "ctx/ns/pod:9093", "ctx/ns/pod:9094"]);
+        assert_eq!(str_to_forward_range("tls+:8443-8444").unwrap().points()[1].to_string(), "tls+localhost:8444");
+        assert_eq!(str_to_forward_range("[::1]:10000-10001").unwrap().ports, 2);
+        assert!(str_to_forward_range(":9094-9092").is_err());
+        assert!(str_to_forward_range(":0-2").is_err());
+        // not a range of the port
+        assert_eq!(str_to_forward_range("exec:nc localhost:1-2").unwrap().point.to_string(), "exec:nc localhost:1-2");
+        assert_eq!(str_to_forward_range("web:exec:seq 1:3-4").unwrap().ports, 1);
+        assert_eq!(str_to_forward_range("web:8080").unwrap().ports, 1);
+    }
+
+    #[test]
+    fn tls_forward_points() {
+        assert!(matches!(str_to_forward_point("tls+:8443"), Ok(ForwardPoint::Tls(p)) if matches!(*p, ForwardPoint::Local(_))));
+        assert!(matches!(
+            str_to_forward_point("tls+ctx/ns/pod:443"),
+            Ok(ForwardPoint::Tls(p)) if matches!(*p, ForwardPoint::Kube(KubeForwardPoint{port: Port::Tcp(443), ..}))
+        ));
+        assert!(str_to_forward_point("tls+tls+:8443").is_err());
+    }
+
+    #[test]
+    fn ipv6_forward_points() {
+        assert!(matches!(str_to_forward_point("[::1]:8080"), Ok(ForwardPoint::Local(a)) if a == ["[::1]:8080".parse().unwrap()]));
+        assert!(matches!(str_to_forward_point("[::]:8080"), Ok(ForwardPoint::Local(a)) if a.len() == 1 && a[0].is_ipv6() && a[0].port() == 8080));
+        // only localhost is dual-stack
+        assert!(matches!(str_to_forward_point("localhost:8080"), Ok(ForwardPoint::Local(a)) if a == localhost(8080)));
+        assert!(matches!(str_to_forward_point(":8080"), Ok(ForwardPoint::Local(a)) if a == localhost(8080)));
+        assert!(matches!(str_to_forward_point("127.0.0.1:8080"), Ok(ForwardPoint::Local(a)) if a == ["127.0.0.1:8080".parse().unwrap()]));
+        assert_eq!(str_to_local_addr("localhost:53"), Ok("127.0.0.1:53".parse().unwrap()));
+        assert!(matches!(
+            str_to_forward_point("ctx/ns/pod:[fd00::1]:5432"),
+            Ok(ForwardPoint::Kube(KubeForwardPoint{pod: Some(_), host: Some(host), port: Port::Tcp(5432), ..})) if host == "fd00::1"
+        ));
+        assert!(matches!(
+            str_to_forward_point("ctx/ns/:[fd00::1]:5432"),
+            Ok(ForwardPoint::Kube(KubeForwardPoint{pod: None, host: Some(_), ..}))
+        ));
+        assert!(matches!(
+            str_to_forward_point("ctx/ns/pod:db:5432"),
+            Ok(ForwardPoint::Kube(KubeForwardPoint{host: Some(host), ..})) if host == "db"
+        ));
+        assert!(str_to_forward_point("[::1:8080").is_err());
+        assert!(str_to_forward_point("ctx/ns/pod:[fd00::1]5432").is_err());
+        assert!(str_to_forward_point("ctx/ns/pod:[db]:5432").is_err());
+    }
+
+    #[test]
+    fn proxies_listen_on_localhost() {
+        <Cli as clap::CommandFactory>::command().debug_assert();
+        let cli = Cli::try_parse_from(["rs", "socks", "localhost:1080", "ctx/ns/pod"]).unwrap();
+        assert!(matches!(cli.command, Some(Commands::Socks{listen, ..}) if listen == localhost(1080)));
+        let cli = Cli::try_parse_from(["rs", "proxy", "127.0.0.1:3128", "ctx/ns/pod"]).unwrap();
+        assert!(matches!(cli.command, Some(Commands::Proxy{listen, ..}) if listen == ["127.0.0.1:3128".parse().unwrap()]));
+    }
+}
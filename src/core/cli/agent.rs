@@ -1,41 +1,82 @@
-use std::{net::{SocketAddr, Ipv4Addr, IpAddr}, process::exit, time::Duration};
+use std::{net::SocketAddr, process::exit, os::unix::prelude::{FromRawFd, IntoRawFd}};
 
-use tokio::time::sleep;
+use tokio::{net::lookup_host, fs::File};
 
-use crate::{endpoint::{stdio::{multiplex_con_to_stdio, stdio_to_con}, AGENT_KILL_PATH}};
+use crate::{endpoint::{socket::localhost, stdio::{multiplex_con_to_stdio, multiplex_unix_to_stdio, stdio_to_con, stdio_to_unix, dial_destinations_from_stdio}}, handshake::{self, handshake, Hello}};
 
 pub struct Agent {
-    port: u16,
-    listen: bool
+    ports: Vec<u16>,
+    listen: bool,
+    host: Option<String>,
+    dynamic: bool,
+    unix: Option<String>,
+    socket_mode: Option<u32>
 }
 
 impl Agent {
-    pub fn new(port: u16, listen: bool) -> Agent {
-        Agent {port, listen}
+    pub fn new(ports: Vec<u16>, listen: bool, host: Option<String>, dynamic: bool, unix: Option<String>, socket_mode: Option<u32>) -> Agent {
+        Agent {ports, listen, host, dynamic, unix, socket_mode}
     }
 
     pub async fn exec(&self) {
+        // refuse to talk to incompatible `rs`
+        if let Err(e) = stdio_handshake().await {
+            eprintln!("Error: agent handshake failed: {:?}", e);
+            exit(1);
+        }
 
-        
-
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), self.port);
+        if self.dynamic {
+            dial_destinations_from_stdio().await;
+            remove_self().await;
+            exit(0);
+        }
+        if let Some(path) = &self.unix {
+            if self.listen {
+                multiplex_unix_to_stdio(path.clone(), self.socket_mode).await;
+                remove_self().await;
+                exit(0);
+            }
+            stdio_to_unix(path.clone()).await;
+            return;
+        }
         if self.listen {
-            // listen for kill signal
-            tokio::spawn(async {
-                loop {
-                    match tokio::fs::File::open(AGENT_KILL_PATH).await {
-                        Ok(_) => {
-                            tokio::fs::remove_file(AGENT_KILL_PATH).await.unwrap();
-                            exit(0);
-                        },
-                        Err(_) => {},
-                    };
-                    sleep(Duration::from_millis(1000)).await;
-                }
-            });  
-           multiplex_con_to_stdio(addr).await;
-           return;
+           multiplex_con_to_stdio(self.ports.clone()).await;
+           remove_self().await;
+           exit(0);
+        }
+        // a connection goes to a single port
+        let port = self.ports.first().copied().unwrap_or_default();
+        if let Some(host) = &self.host {
+            // every resolved address is tried, IPv4 and IPv6 alike
+            let addrs: Vec<SocketAddr> = match lookup_host((host.as_str(), port)).await {
+                Ok(addrs) => addrs.collect(),
+                Err(_) => vec![],
+            };
+            if addrs.is_empty() {
+                eprintln!("Error: failed to resolve {host}");
+                exit(1);
+            }
+            stdio_to_con(addrs).await;
+            return;
         }
-        stdio_to_con(addr).await;
+        stdio_to_con(localhost(port)).await;
     }
-}
\ No newline at end of file
+}
+
+// Buffered stdin could read ahead into mux frames sent right after the hello, the raw fds are read
+// for exactly the hello and left open for the forwarding
+async fn stdio_handshake() -> Result<Hello, handshake::Error> {
+    let mut input = unsafe { File::from_raw_fd(0) };
+    let mut output = unsafe { File::from_raw_fd(1) };
+    let hello = handshake(&mut input, &mut output, 0).await?;
+    _ = input.into_std().await.into_raw_fd();
+    _ = output.into_std().await.into_raw_fd();
+    Ok(hello)
+}
+
+// the session copy of the agent is not needed anymore
+async fn remove_self() {
+    if let Ok(path) = std::env::current_exe() {
+        _ = tokio::fs::remove_file(path).await;
+    }
+}
@@ -66,7 +66,7 @@ impl Complete {
     }
     
     fn complete_commands(&self, com: &str) {
-        let commands = vec!["pf ".to_string(), "ls ".to_string(), "cp ".to_string()].into_iter().filter(|c| c.starts_with(com));
+        let commands = vec!["pf ".to_string(), "ls ".to_string(), "cp ".to_string(), "socks ".to_string(), "proxy ".to_string(), "services ".to_string()].into_iter().filter(|c| c.starts_with(com));
         print_options(&commands.collect());
         
     }
@@ -1,4 +1,4 @@
-use std::fmt::Write;
+use std::{fmt::Write, time::Duration};
 
 use clap::{CommandFactory, ErrorKind};
 use indicatif::{ProgressBar, ProgressStyle, ProgressState};
@@ -41,7 +41,8 @@ impl Cp {
         Cp {kube, doc}
     }
 
-    pub async fn exec(&self, src: CopyPoint, dst: CopyPoint) -> Result<(), Box<dyn std::error::Error>> {
+    // Limit is in bytes per second
+    pub async fn exec(&self, src: CopyPoint, dst: CopyPoint, limit: Option<u64>) -> Result<(), Box<dyn std::error::Error>> {
         let mut cmd = Cli::command();
         print!("Copying from: ");
         let from = match get_source(&self.doc, &self.kube, src).await {
@@ -59,13 +60,13 @@ impl Cp {
         };
         println!();
         let size = from.get_size();
-        let mut recv = pipe_copy(from, to).await;
+        let mut recv = pipe_copy(from, to, limit).await;
         tokio::spawn(async move {
             let mut total: u64 = 0;
             let pb = ProgressBar::new(size);
-            pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} {binary_bytes_per_sec} ({eta})")
                 .unwrap()
-                .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+                .with_key("eta", move |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", eta(state, limit).as_secs_f64()).unwrap())
                 .progress_chars("#>-"));
             while let Some(prog) = recv.recv().await {
                 total += prog as u64;
@@ -80,6 +81,19 @@ impl Cp {
 }
 
 
+// Measured rate jumps over the limit when bytes are buffered, the limit is what the copy keeps to
+fn eta(state: &ProgressState, limit: Option<u64>) -> Duration {
+    let rate = match limit {
+        Some(limit) => state.per_sec().min(limit as f64),
+        None => return state.eta(),
+    };
+    let left = state.len().unwrap_or_default().saturating_sub(state.pos());
+    match rate > 0.0 {
+        true => Duration::from_secs_f64(left as f64 / rate),
+        false => Duration::ZERO,
+    }
+}
+
 async fn get_source(doc: &DockerEndpoint, kube: &KubeConfigs, src: CopyPoint) -> Result<PipeCopySource, Error> {
     match src {
         CopyPoint::Kube(kp) => {
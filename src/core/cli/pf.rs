@@ -1,18 +1,47 @@
+use std::{fmt, future::Future, sync::{Arc, Mutex}, time::Duration};
 
 use clap::{ErrorKind, CommandFactory};
-use tokio::{net::TcpSocket};
+use futures::future::{join_all, try_join_all};
+use openssl::ssl::{SslAcceptor, SslConnector};
+use tokio::{net::{TcpStream, UnixStream}, sync::{Semaphore, OwnedSemaphorePermit}};
 
-use crate::endpoint::{kube::KubeConfigs, AGENT_PATH, PipeEndpoint, self, stdio::StdioPipeEndpoint, connect, socket::TCPConnectionProvider, docker::DockerEndpoint};
+use crate::endpoint::{kube::{KubeConfigs, RelayPod}, AgentSession, PipeEndpoint, self, stdio::{StdioPipeEndpoint, ProcessPipeEndpoint}, socket::{self, TCPConnectionProvider, TcpListeners, UnixConnectionProvider}, docker::{DockerEndpoint, Sidecar}, tls::{self, TlsPipeEndpoint}, inspect::Inspector, pcap::PcapWriter, shaping::{Shaping, ShapedPipeEndpoint}, Tap, TapWriter, ConnectionAddresses, ConnectOptions, connect_with};
+use crate::shutdown::Shutdown;
 
-use super::{ForwardPoint, Cli, KubeForwardPoint, DockerForwardPoint};
+use super::{ForwardPoint, ForwardRange, Cli, KubeForwardPoint, DockerForwardPoint, Port, RelayArgs, EphemeralArgs, SidecarArgs, AgentArgs, SocketArgs, TlsArgs, TrafficArgs, LimitArgs, Output};
+
+// Pause after failing to accept a connection, e.g. when out of file descriptors
+static ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 pub struct Pf {
-    kube: KubeConfigs
+    kube: KubeConfigs,
+    relay: RelayArgs,
+    ephemeral: EphemeralArgs,
+    sidecar: SidecarArgs,
+    socket: SocketArgs,
+    tls: TlsArgs,
+    traffic: TrafficArgs,
+    limits: LimitArgs,
+    output: Output,
+    session: AgentSession,
+    shutdown: Shutdown
 }
 
-enum Error {
+#[derive(Debug)]
+pub(super) enum Error {
     Docker(endpoint::docker::Error),
-    Kube(endpoint::kube::Error)
+    Kube(endpoint::kube::Error),
+    Io(String)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Docker(e) => write!(f, "{:?}", e),
+            Error::Kube(e) => write!(f, "{:?}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
 }
 
 impl From<endpoint::docker::Error> for Error {
@@ -21,6 +50,24 @@ impl From<endpoint::docker::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e.to_string())
+    }
+}
+
+impl From<endpoint::stdio::Error> for Error {
+    fn from(e: endpoint::stdio::Error) -> Self {
+        Error::Io(format!("{:?}", e))
+    }
+}
+
+impl From<tls::Error> for Error {
+    fn from(e: tls::Error) -> Self {
+        Error::Io(format!("{:?}", e))
+    }
+}
+
 impl From<endpoint::kube::Error> for Error{
     fn from(e: endpoint::kube::Error) -> Self {
         Error::Kube(e)
@@ -29,90 +76,548 @@ impl From<endpoint::kube::Error> for Error{
 
 
 impl Pf {
-    pub fn new(kube: KubeConfigs) -> Pf {
-        Pf {kube}
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(kube: KubeConfigs, relay: RelayArgs, ephemeral: EphemeralArgs, sidecar: SidecarArgs, agent: AgentArgs, socket: SocketArgs, tls: TlsArgs, traffic: TrafficArgs, limits: LimitArgs, output: Output) -> Pf {
+        let shutdown = Shutdown::new();
+        let session = AgentSession::new(agent.agent_dir, agent.agent_memfd, shutdown.clone());
+        Pf {kube, relay, ephemeral, sidecar, socket, tls, traffic, limits, output, session, shutdown}
     }
 
-    pub async fn exec(&self, origin: ForwardPoint, dst: ForwardPoint) -> Result<(), Box<dyn std::error::Error>> { 
+    pub async fn exec(&self, origin: ForwardRange, dst: ForwardRange) -> Result<(), Box<dyn std::error::Error>> { 
         let mut cmd = Cli::command();
-        if matches!(origin, ForwardPoint::Stdio) && matches!(dst, ForwardPoint::Stdio) {
+        if origin.ports != dst.ports {
+            cmd.error(ErrorKind::ValueValidation, "Port ranges of ORIGIN and DESTINATION differ in length").exit();
+        }
+        let ports = origin.ports;
+        let ready = Ready {
+            output: self.output,
+            stdio: matches!(origin.point.without_tls(), ForwardPoint::Stdio) || matches!(dst.point.without_tls(), ForwardPoint::Stdio),
+            origin: origin.to_string(),
+            destination: dst.to_string(),
+            listeners: match origin.point.without_tls() {
+                ForwardPoint::Kube(_) | ForwardPoint::Docker(_) => 1,
+                _ => ports as usize,
+            },
+            listening: Arc::new(Mutex::new((0, vec![])))
+        };
+        let (origin, dst) = (origin.point, dst.point);
+        if matches!(origin.without_tls(), ForwardPoint::Stdio) && matches!(dst.without_tls(), ForwardPoint::Stdio) {
             cmd.error(ErrorKind::ArgumentConflict, "Both forward points could not be STDIO").exit();
         }
-        match accept_origin_endpoint(origin, dst, &self.kube).await {
-            Ok(_) => {},
-            Err(_) => {
-                cmd.error(ErrorKind::Io, "Port forward failed");
+        if matches!(origin.without_tls(), ForwardPoint::Kube(KubeForwardPoint{pod: None, ..})) {
+            cmd.error(ErrorKind::ArgumentConflict, "Relay pod could only be a DESTINATION").exit();
+        }
+        if matches!(origin.without_tls(), ForwardPoint::Kube(KubeForwardPoint{host: Some(_), ..})) {
+            cmd.error(ErrorKind::ArgumentConflict, "Jump host could only be a DESTINATION").exit();
+        }
+        if matches!(origin, ForwardPoint::Tls(_)) && (self.tls.tls_cert.is_none() || self.tls.tls_key.is_none()) {
+            cmd.error(ErrorKind::MissingRequiredArgument, "TLS ORIGIN requires --tls-cert and --tls-key").exit();
+        }
+        // pods and containers have no host name their certificate could be verified against
+        if let ForwardPoint::Tls(tls_dst) = &dst {
+            if self.tls.tls_sni.is_none() && server_name(tls_dst).is_none() && !self.tls.tls_insecure && !self.tls.tls_insecure_hostname {
+                cmd.error(ErrorKind::MissingRequiredArgument, "TLS DESTINATION without host name requires --tls-sni or --tls-insecure-hostname").exit();
+            }
+        }
+        // only ports picked for local listeners could be reported
+        if has_port_zero(origin.without_tls(), false) || has_port_zero(dst.without_tls(), true) {
+            cmd.error(ErrorKind::ValueValidation, "Port 0 could only be used by a local ORIGIN").exit();
+        }
+        let tls = match TlsContexts::new(&self.tls, &origin, &dst) {
+            Ok(tls) => tls,
+            Err(e) => cmd.error(ErrorKind::Io, format!("Failed to load TLS certificates: {:?}", e)).exit(),
+        };
+        let mut taps: Vec<Arc<dyn Tap>> = vec![];
+        if let Some(view) = self.traffic.inspect {
+            taps.push(Arc::new(Inspector::new(view)));
+        }
+        if let Some(path) = &self.traffic.pcap {
+            match PcapWriter::create(path) {
+                Ok(writer) => taps.push(Arc::new(writer)),
+                Err(e) => cmd.error(ErrorKind::Io, format!("Failed to create {}: {}", path, e)).exit(),
+            }
+        }
+        // systemd passes its sockets once, ports of a range take one each in order
+        let mut activated: Vec<Option<TcpListeners>> = match origin.without_tls() {
+            ForwardPoint::Local(_) => match TcpListeners::activated().map(|l| l.per_port(ports as usize)) {
+                Some(Ok(listeners)) => listeners.into_iter().map(Some).collect(),
+                Some(Err(e)) => cmd.error(ErrorKind::ValueValidation, e).exit(),
+                None => vec![],
             },
+            _ => vec![],
+        };
+        activated.resize_with(ports as usize, || None);
+        let writers: Vec<TapWriter> = taps.iter().map(|t| t.writer().clone()).collect();
+        let layers = Layers {
+            ready,
+            tls,
+            taps,
+            shaping: self.traffic.shaping(),
+            connections: self.limits.max_connections.map(|n| Arc::new(Semaphore::new(n as usize))),
+            idle_timeout: self.limits.idle_timeout,
+            lifetime: self.limits.max_lifetime
+        };
+        let forward = async {
+            let origin = prepare_origin_endpoint(origin, &self.kube, &self.session, &self.shutdown, &self.ephemeral, &self.sidecar).await
+                .map_err(|e| format!("Failed to prepare origin: {}", e))?;
+            let dst = prepare_destination_endpoint(dst, &self.kube, &self.session, &self.shutdown, &self.relay, &self.ephemeral, &self.sidecar).await
+                .map_err(|e| format!("Failed to prepare destination: {}", e))?;
+            // one agent listens on every port of a range, local origins listen on each port themselves
+            let dsts = ForwardRange { point: dst, ports }.points();
+            let forwards: Vec<(ForwardPoint, Vec<ForwardPoint>, Option<TcpListeners>)> = match origin.without_tls() {
+                ForwardPoint::Kube(_) | ForwardPoint::Docker(_) => vec![(origin, dsts, None)],
+                _ => ForwardRange { point: origin, ports }.points().into_iter()
+                    .zip(dsts)
+                    .zip(activated)
+                    .map(|((origin, dst), activated)| (origin, vec![dst], activated))
+                    .collect(),
+            };
+            let forwards = forwards.into_iter().map(|(origin, dsts, activated)| {
+                accept_origin_endpoint(origin, dsts, activated, &self.kube, &self.session, &self.shutdown, &self.socket, &layers)
+            });
+            try_join_all(forwards).await.map(|_| ())
+                .map_err(|e| format!("Port forward failed: {}", e))
+        };
+        // agents, pods and containers of the session are cleaned up however it ends
+        let res = self.shutdown.run_until_signal(forward).await;
+        // traffic of the last connections could still be queued
+        join_all(writers.iter().map(TapWriter::flush)).await;
+        if let Err(e) = res {
+            cmd.error(ErrorKind::Io, e).exit();
         }
         Ok(())
     }
 }
 
-async fn get_destination_endpoint(p: ForwardPoint, kube: &KubeConfigs) -> Result<Box<dyn PipeEndpoint>, Error> {
+fn register_relay(shutdown: &Shutdown, relay: RelayPod) {
+    shutdown.register(format!("relay pod {}", relay.name()), async move {
+        relay.delete().await.map_err(|e| format!("{:?}", e))
+    });
+}
+
+fn register_sidecar(shutdown: &Shutdown, sidecar: Sidecar) {
+    shutdown.register(format!("sidecar container {}", sidecar.name()), async move {
+        sidecar.remove().await.map_err(|e| format!("{:?}", e))
+    });
+}
+
+// TLS contexts of tls+ forward points, created once per session
+#[derive(Clone, Default)]
+struct TlsContexts {
+    acceptor: Option<SslAcceptor>,
+    connector: Option<SslConnector>,
+    server_name: Option<String>
+}
+
+impl TlsContexts {
+    fn new(args: &TlsArgs, origin: &ForwardPoint, dst: &ForwardPoint) -> Result<TlsContexts, tls::Error> {
+        let mut contexts = TlsContexts::default();
+        if let (ForwardPoint::Tls(_), Some(cert), Some(key)) = (origin, &args.tls_cert, &args.tls_key) {
+            contexts.acceptor = Some(tls::acceptor(cert, key)?);
+        }
+        if let ForwardPoint::Tls(dst) = dst {
+            contexts.connector = Some(tls::connector(args.tls_ca.as_deref(), args.tls_insecure)?);
+            contexts.server_name = args.tls_sni.clone().or_else(|| server_name(dst));
+        }
+        Ok(contexts)
+    }
+
+    // Terminates TLS of connections accepted on tls+ ORIGIN
+    fn accept(&self, con: Box<dyn PipeEndpoint>) -> Box<dyn PipeEndpoint> {
+        match &self.acceptor {
+            Some(acceptor) => Box::new(TlsPipeEndpoint::accept(con, acceptor.clone())),
+            None => con,
+        }
+    }
+}
+
+fn has_port_zero(p: &ForwardPoint, local: bool) -> bool {
     match p {
+        ForwardPoint::Kube(KubeForwardPoint{port: Port::Tcp(port), ..}) | ForwardPoint::Docker(DockerForwardPoint{port: Port::Tcp(port), ..}) => *port == 0,
+        ForwardPoint::Local(addrs) => local && addrs.iter().any(|a| a.port() == 0),
+        _ => false,
+    }
+}
+
+// Announces that ORIGIN accepts connections, so scripts do not have to guess when
+#[derive(Clone)]
+struct Ready {
+    output: Output,
+    // stdout carries the forwarded data
+    stdio: bool,
+    origin: String,
+    destination: String,
+    // reported once each port of a range listens
+    listeners: usize,
+    listening: Arc<Mutex<(usize, Vec<String>)>>
+}
+
+impl Ready {
+    // Listening addresses are empty for origins forwarding a single connection
+    fn report(&self, listening: Vec<String>) {
+        let listening = {
+            let mut reported = self.listening.lock().unwrap();
+            reported.0 += 1;
+            reported.1.extend(listening);
+            if reported.0 < self.listeners {
+                return;
+            }
+            reported.1.clone()
+        };
+        let line = match self.output {
+            Output::Text if listening.is_empty() => return,
+            Output::Text => format!("Listening on {}", listening.join(", ")),
+            Output::Json => serde_json::json!({
+                "event": "ready",
+                "origin": self.origin,
+                "destination": self.destination,
+                "listening": listening,
+                "pid": std::process::id()
+            }).to_string(),
+        };
+        if self.stdio {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+        socket::notify_systemd(&format!("READY=1\nMAINPID={}", std::process::id()));
+    }
+}
+
+// Applied to each forwarded connection
+#[derive(Clone)]
+struct Layers {
+    ready: Ready,
+    tls: TlsContexts,
+    taps: Vec<Arc<dyn Tap>>,
+    // faults added between the taps and DESTINATION
+    shaping: Option<Shaping>,
+    // permits of connections forwarded at once
+    connections: Option<Arc<Semaphore>>,
+    idle_timeout: Option<Duration>,
+    lifetime: Option<Duration>
+}
+
+impl Layers {
+    // Copies between the endpoints, terminating TLS of the origin, passing bytes to the taps and shaping them
+    fn forward(&self, from: Box<dyn PipeEndpoint>, to: Box<dyn PipeEndpoint>, addresses: Option<ConnectionAddresses>) -> impl Future<Output = u64> {
+        let from = self.tls.accept(from);
+        let to: Box<dyn PipeEndpoint> = match &self.shaping {
+            Some(shaping) => Box::new(ShapedPipeEndpoint::new(to, shaping.clone())),
+            None => to,
+        };
+        let options = ConnectOptions {
+            taps: self.taps.clone(),
+            addresses,
+            idle_timeout: self.idle_timeout,
+            lifetime: self.lifetime
+        };
+        connect_with(from, to, options)
+    }
+
+    // Waits until another connection could be forwarded
+    async fn permit(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.connections {
+            Some(connections) => connections.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+}
+
+// Jump hosts and local addresses are verified, pods and containers only by certificate chain
+fn server_name(p: &ForwardPoint) -> Option<String> {
+    match p {
+        ForwardPoint::Kube(KubeForwardPoint{host: Some(host), ..}) => Some(host.clone()),
+        ForwardPoint::Local(addrs) => addrs.first().map(|a| match a.ip().is_loopback() {
+            true => "localhost".to_string(),
+            false => a.ip().to_string(),
+        }),
+        _ => None,
+    }
+}
+
+// Installs agent in the pod, in an ephemeral container if requested
+async fn install_kube_agent(k: KubeForwardPoint, kube: &KubeConfigs, session: &AgentSession, ephemeral: &EphemeralArgs) -> Result<KubeForwardPoint, Error> {
+    let KubeForwardPoint{context, namespace, pod, host, container, port} = k;
+    let pod = pod.ok_or(endpoint::kube::Error::RelayError("Relay pod was not created".to_string()))?;
+    let container = match ephemeral.ephemeral {
+        true => Some(kube.create_ephemeral_container(context.clone(), namespace.clone(), pod.clone(), ephemeral.ephemeral_image.clone()).await?),
+        false => container,
+    };
+    kube.install_agent(context.clone(), namespace.clone(), pod.clone(), container.clone(), session).await?;
+    Ok(KubeForwardPoint{context, namespace, pod: Some(pod), host, container, port})
+}
+
+// Installs agent in the container, in a sidecar container if it is not possible
+async fn install_docker_agent(d: DockerForwardPoint, session: &AgentSession, shutdown: &Shutdown, sidecar: &SidecarArgs) -> Result<DockerForwardPoint, Error> {
+    let DockerForwardPoint{container, port} = d;
+    let doc = DockerEndpoint::new();
+    let err = match doc.install_agent(&container, session).await {
+        Ok(_) => return Ok(DockerForwardPoint{container, port}),
+        Err(e) => e,
+    };
+    log::info!("Agent install into {} failed: {:?}, starting sidecar", container, err);
+    let sidecar = doc.start_sidecar(&container, &sidecar.sidecar_image).await?;
+    let container = sidecar.name().to_owned();
+    register_sidecar(shutdown, sidecar);
+    doc.install_agent(&container, session).await?;
+    Ok(DockerForwardPoint{container, port})
+}
+
+// Installs agents once per session
+pub(super) async fn prepare_origin_endpoint(p: ForwardPoint, kube: &KubeConfigs, session: &AgentSession, shutdown: &Shutdown, ephemeral: &EphemeralArgs, sidecar: &SidecarArgs) -> Result<ForwardPoint, Error> {
+    match p {
+        // processes are started without the agent
+        ForwardPoint::Kube(KubeForwardPoint{port: Port::Exec(_), ..}) | ForwardPoint::Docker(DockerForwardPoint{port: Port::Exec(_), ..}) => Ok(p),
+        ForwardPoint::Kube(k) => {
+            Ok(ForwardPoint::Kube(install_kube_agent(k, kube, session, ephemeral).await?))
+        },
+        ForwardPoint::Docker(d) => {
+            Ok(ForwardPoint::Docker(install_docker_agent(d, session, shutdown, sidecar).await?))
+        },
+        ForwardPoint::Tls(p) => {
+            let p = Box::pin(prepare_origin_endpoint(*p, kube, session, shutdown, ephemeral, sidecar)).await?;
+            Ok(ForwardPoint::Tls(Box::new(p)))
+        },
+        _ => Ok(p)
+    }
+}
+
+// Installs agents and creates relay pods once per session
+pub(super) async fn prepare_destination_endpoint(p: ForwardPoint, kube: &KubeConfigs, session: &AgentSession, shutdown: &Shutdown, relay: &RelayArgs, ephemeral: &EphemeralArgs, sidecar: &SidecarArgs) -> Result<ForwardPoint, Error> {
+    match p {
+        ForwardPoint::Kube(KubeForwardPoint{context, namespace, pod: None, host, container, port}) => {
+            let RelayArgs{relay_image, relay_node_selector, relay_timeout} = relay.clone();
+            let relay = kube.create_relay(context.clone(), namespace.clone(), relay_image, relay_node_selector, relay_timeout).await?;
+            let pod = relay.name().to_owned();
+            register_relay(shutdown, relay);
+            kube.install_agent(context.clone(), namespace.clone(), pod.clone(), container.clone(), session).await?;
+            Ok(ForwardPoint::Kube(KubeForwardPoint{context, namespace, pod: Some(pod), host, container, port}))
+        },
+        ForwardPoint::Kube(k) if k.host.is_some() || matches!(k.port, Port::Unix(_)) => {
+            Ok(ForwardPoint::Kube(install_kube_agent(k, kube, session, ephemeral).await?))
+        },
+        ForwardPoint::Docker(DockerForwardPoint{port: Port::Exec(_), ..}) => Ok(p),
+        ForwardPoint::Docker(d) => {
+            Ok(ForwardPoint::Docker(install_docker_agent(d, session, shutdown, sidecar).await?))
+        },
+        ForwardPoint::Tls(p) => {
+            let p = Box::pin(prepare_destination_endpoint(*p, kube, session, shutdown, relay, ephemeral, sidecar)).await?;
+            Ok(ForwardPoint::Tls(Box::new(p)))
+        },
+        _ => Ok(p)
+    }
+}
+
+// Arguments of agents listening for connections on the port
+fn listen_args(port: &Port, ports: usize, socket: &SocketArgs) -> Vec<String> {
+    let mut args = vec!["-l".to_string()];
+    match port {
+        Port::Tcp(first) => (0..ports as u16).for_each(|i| args.extend(Port::Tcp(first + i).agent_args())),
+        _ => args.extend(port.agent_args()),
+    }
+    if let (Port::Unix(_), Some(mode)) = (port, socket.socket_mode) {
+        args.extend(["--socket-mode".to_string(), format!("{:o}", mode)]);
+    }
+    args
+}
+
+// Agents tag connections with the port they were accepted on, ports of a range map to the destinations in order
+fn tagged_destination<'a>(port: &Port, tag: &str, destinations: &'a [ForwardPoint]) -> Option<&'a ForwardPoint> {
+    match port {
+        Port::Tcp(first) => destinations.get(usize::from(tag.parse::<u16>().ok()?.checked_sub(*first)?)),
+        _ => destinations.first(),
+    }
+}
+
+async fn get_destination_endpoint(p: ForwardPoint, kube: &KubeConfigs, session: &AgentSession, layers: &Layers) -> Result<Box<dyn PipeEndpoint>, Error> {
+    match p {
+        ForwardPoint::Tls(p) => {
+            let con = Box::pin(get_destination_endpoint(*p, kube, session, layers)).await?;
+            let connector = layers.tls.connector.clone().ok_or(tls::Error::SslError("TLS connector was not created".to_string()))?;
+            Ok(Box::new(TlsPipeEndpoint::connect(con, connector, layers.tls.server_name.clone())))
+        },
         ForwardPoint::Kube(k) => {
-            let KubeForwardPoint{context, namespace, pod, port} = k;
+            let KubeForwardPoint{context, namespace, pod, host, container, port} = k;
+            let pod = pod.ok_or(endpoint::kube::Error::RelayError("Relay pod was not created".to_string()))?;
+            let port = match (host, port) {
+                (Some(host), Port::Tcp(port)) => {
+                    let con = kube.connect(context, namespace, pod, container, session, &["-p", &port.to_string(), "--host", &host]).await?;
+                    return Ok(Box::new(con));
+                },
+                (_, Port::Unix(path)) => {
+                    let con = kube.connect(context, namespace, pod, container, session, &["--unix", &path]).await?;
+                    return Ok(Box::new(con));
+                },
+                (_, Port::Exec(command)) => {
+                    let con = kube.exec(context, namespace, pod, container, &command).await?;
+                    return Ok(Box::new(con));
+                },
+                (None, Port::Tcp(port)) => port,
+            };
             let pf = kube.get_port_forward(context, namespace, pod, port).await?;
-            return Ok(Box::new(pf));
+            Ok(Box::new(pf))
         },
         ForwardPoint::Stdio => {
-            return Ok(Box::new(StdioPipeEndpoint {}));
+            Ok(Box::new(StdioPipeEndpoint {}))
         },
-        ForwardPoint::Local(l) => {
-            let soc = TcpSocket::new_v4().expect("Failed to create local connection");
-            let con = soc.connect(l).await.expect(&format!("Failed to connect to {l}"));
-            return Ok(Box::new(con));
+        ForwardPoint::Local(addrs) => {
+            let con = TcpStream::connect(&addrs[..]).await?;
+            Ok(Box::new(con))
+        },
+        ForwardPoint::Unix(path) => {
+            let con = UnixStream::connect(&path).await?;
+            Ok(Box::new(con))
+        },
+        ForwardPoint::Exec(command) => {
+            Ok(Box::new(ProcessPipeEndpoint::spawn(&command)?))
+        },
+        ForwardPoint::Docker(DockerForwardPoint{port: Port::Exec(command), container}) => {
+            let doc = DockerEndpoint::new();
+            let con = doc.exec(&container, &command).await?;
+            Ok(Box::new(con))
         },
         ForwardPoint::Docker(DockerForwardPoint{port, container}) => {
             let doc = DockerEndpoint::new();
-            let agent_exec = vec![AGENT_PATH.to_string(), "agent".to_string(), "-p".to_string(), port.to_string()];
-            doc.install_agent(&container).await?;
-            let con = doc.connect(&container, agent_exec).await?;
-            return Ok(Box::new(con));
+            let args = port.agent_args();
+            let con = doc.connect(&container, session, &args.iter().map(String::as_str).collect::<Vec<_>>()).await?;
+            Ok(Box::new(con))
+        },
+    }
+}
+
+// Forwards a connection accepted on ORIGIN in the background, failing to reach DESTINATION only closes it
+async fn forward_connection(from: Box<dyn PipeEndpoint>, addresses: Option<ConnectionAddresses>, destination: &ForwardPoint, kube: &KubeConfigs, session: &AgentSession, shutdown: &Shutdown, layers: &Layers) {
+    let permit = layers.permit().await;
+    let to = match get_destination_endpoint(destination.clone(), kube, session, layers).await {
+        Ok(to) => to,
+        Err(e) => {
+            eprintln!("Failed to connect to DESTINATION: {}", e);
+            return;
         },
     };
+    let forward = layers.forward(from, to, addresses);
+    shutdown.spawn_connection(async move {
+        forward.await;
+        drop(permit);
+    });
 }
 
-async fn accept_origin_endpoint(origin: ForwardPoint, destination: ForwardPoint, kube: &KubeConfigs) -> Result<(), Error> {
+// Agents in pods and containers take the destinations of every port of a range, other origins a single one.
+// Sockets passed by systemd are listened on instead of the local ORIGIN
+#[allow(clippy::too_many_arguments)]
+async fn accept_origin_endpoint(origin: ForwardPoint, destinations: Vec<ForwardPoint>, activated: Option<TcpListeners>, kube: &KubeConfigs, session: &AgentSession, shutdown: &Shutdown, socket: &SocketArgs, layers: &Layers) -> Result<(), Error> {
+    let destination = destinations[0].clone();
     match origin {
+        // TLS is terminated on each connection accepted on the wrapped origin
+        ForwardPoint::Tls(origin) => {
+            Box::pin(accept_origin_endpoint(*origin, destinations, activated, kube, session, shutdown, socket, layers)).await?;
+        },
+        // a process is a single connection like STDIO
+        ForwardPoint::Kube(KubeForwardPoint{context, namespace, pod: Some(pod), container, port: Port::Exec(command), ..}) => {
+            let from = kube.exec(context, namespace, pod, container, &command).await?;
+            let to = get_destination_endpoint(destination, kube, session, layers).await?;
+            layers.ready.report(vec![]);
+            layers.forward(Box::new(from), to, None).await;
+        },
+        ForwardPoint::Docker(DockerForwardPoint{port: Port::Exec(command), container}) => {
+            let from = DockerEndpoint::new().exec(&container, &command).await?;
+            let to = get_destination_endpoint(destination, kube, session, layers).await?;
+            layers.ready.report(vec![]);
+            layers.forward(Box::new(from), to, None).await;
+        },
+        ForwardPoint::Exec(command) => {
+            let from = ProcessPipeEndpoint::spawn(&command)?;
+            let to = get_destination_endpoint(destination, kube, session, layers).await?;
+            layers.ready.report(vec![]);
+            layers.forward(Box::new(from), to, None).await;
+        },
         ForwardPoint::Kube(k) => {
-            let KubeForwardPoint{context, namespace, pod, port} = k;
-            let agent_exec = vec![AGENT_PATH.to_string(), "agent".to_string(), "-l".to_string(), "-p".to_string(), port.to_string()];
-            kube.install_agent(context.clone(), namespace.clone(), pod.clone()).await?;
-            let mut rec = kube.get_connections(context, namespace, pod, &agent_exec).await?;
-            while let Some(con) = rec.recv().await {
-                let to = get_destination_endpoint(destination.clone(), kube).await?;
-                tokio::spawn(async {
-                    connect(Box::new(con), to).await;
-                });
+            let listening = ForwardRange { point: ForwardPoint::Kube(k.clone()), ports: destinations.len() as u16 }.points();
+            let KubeForwardPoint{context, namespace, pod, container, port, ..} = k;
+            let pod = pod.ok_or(endpoint::kube::Error::RelayError("Relay pod could only be a destination".to_string()))?;
+            let args = listen_args(&port, destinations.len(), socket);
+            let mut rec = kube.get_connections(context, namespace, pod, container, session, &args.iter().map(String::as_str).collect::<Vec<_>>()).await?;
+            layers.ready.report(listening.iter().map(ToString::to_string).collect());
+            while let Some((con, tag)) = rec.recv().await {
+                match tagged_destination(&port, &tag, &destinations) {
+                    Some(destination) => forward_connection(Box::new(con), None, destination, kube, session, shutdown, layers).await,
+                    None => eprintln!("Agent accepted connection on unexpected port {}", tag),
+                }
             }
         },
         ForwardPoint::Stdio => {
             let from = StdioPipeEndpoint{};
-            let to = get_destination_endpoint(destination, kube).await?;
-            connect(Box::new(from), to).await;
-        },
-        ForwardPoint::Local(addr) => {
-            let provider = TCPConnectionProvider::new(addr).listen_for_connections().await;
-            while let Ok((con, _)) = provider.accept().await {
-                let to = get_destination_endpoint(destination.clone(), kube).await?;
-                tokio::spawn(async {
-                    connect(Box::new(con), to).await;
-                });
+            let to = get_destination_endpoint(destination, kube, session, layers).await?;
+            layers.ready.report(vec![]);
+            layers.forward(Box::new(from), to, None).await;
+        },
+        ForwardPoint::Local(addrs) => {
+            // sockets of systemd socket activation replace the ones ORIGIN would listen on
+            let provider = match activated {
+                Some(listeners) => {
+                    listeners.warn_unless_listening_on(&addrs);
+                    listeners
+                },
+                None => TCPConnectionProvider::from_addresses(addrs).listen_for_connections().await,
+            };
+            layers.ready.report(provider.local_addrs().iter().map(|a| a.to_string()).collect());
+            loop {
+                let (con, peer) = match provider.accept().await {
+                    Ok(con) => con,
+                    // e.g. out of file descriptors, the listener keeps going
+                    Err(e) => {
+                        eprintln!("Failed to accept connection: {}", e);
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    },
+                };
+                let addresses = con.local_addr().ok().map(|local| ConnectionAddresses { peer, local });
+                forward_connection(Box::new(con), addresses, &destination, kube, session, shutdown, layers).await;
             }
         },
-        ForwardPoint::Docker(DockerForwardPoint{port, container}) => {
+        ForwardPoint::Unix(path) => {
+            let listener = UnixConnectionProvider::new(path.clone(), socket.socket_mode).listen_for_connections().await;
+            layers.ready.report(vec![format!("unix:{}", path)]);
+            shutdown.register(format!("socket {}", path), async move {
+                tokio::fs::remove_file(path).await.map_err(|e| e.to_string())
+            });
+            while let Ok((con, _)) = listener.accept().await {
+                forward_connection(Box::new(con), None, &destination, kube, session, shutdown, layers).await;
+            }
+        },
+        ForwardPoint::Docker(d) => {
+            let listening = ForwardRange { point: ForwardPoint::Docker(d.clone()), ports: destinations.len() as u16 }.points();
+            let DockerForwardPoint{port, container} = d;
             let doc = DockerEndpoint::new();
-            let agent_exec = vec![AGENT_PATH.to_string(), "agent".to_string(), "-l".to_string(), "-p".to_string(), port.to_string()];
-            doc.install_agent(&container).await?;
-            let mut rec = doc.get_connections(&container, agent_exec).await?;
-            while let Some(con) = rec.recv().await {
-                let to = get_destination_endpoint(destination.clone(), kube).await?;
-                tokio::spawn(async {
-                    connect(Box::new(con), to).await;
-                });
+            let args = listen_args(&port, destinations.len(), socket);
+            let mut rec = doc.get_connections(&container, session, &args.iter().map(String::as_str).collect::<Vec<_>>()).await?;
+            layers.ready.report(listening.iter().map(ToString::to_string).collect());
+            while let Some((con, tag)) = rec.recv().await {
+                match tagged_destination(&port, &tag, &destinations) {
+                    Some(destination) => forward_connection(Box::new(con), None, destination, kube, session, shutdown, layers).await,
+                    None => eprintln!("Agent accepted connection on unexpected port {}", tag),
+                }
             }
         },
     };
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_agent_listens_on_every_port_of_a_range() {
+        let socket = SocketArgs { socket_mode: None };
+        assert_eq!(listen_args(&Port::Tcp(9092), 3, &socket), ["-l", "-p", "9092", "-p", "9093", "-p", "9094"]);
+        assert_eq!(listen_args(&Port::Tcp(65535), 1, &socket), ["-l", "-p", "65535"]);
+
+        let destinations = ForwardRange { point: ForwardPoint::Local(socket::localhost(19092)), ports: 3 }.points();
+        let routed = tagged_destination(&Port::Tcp(9092), "9093", &destinations).unwrap();
+        assert_eq!(routed.tcp_port(), Some(19093));
+        assert!(tagged_destination(&Port::Tcp(9092), "9095", &destinations).is_none());
+        assert!(tagged_destination(&Port::Tcp(9092), "9091", &destinations).is_none());
+        assert!(tagged_destination(&Port::Tcp(9092), "", &destinations).is_none());
+    }
+}
@@ -0,0 +1,77 @@
+use std::net::SocketAddr;
+
+use clap::{ErrorKind, CommandFactory};
+use tokio::{net::TcpStream, io::{AsyncWriteExt, BufReader}};
+
+use crate::endpoint::{kube::KubeConfigs, AgentSession, connect, socket::TCPConnectionProvider, http, socks};
+use crate::mux::MuxConnector;
+use crate::shutdown::Shutdown;
+
+use super::{AgentPoint, Cli, RelayArgs, EphemeralArgs, SidecarArgs, AgentArgs};
+use super::socks::{open_connector, dial};
+
+pub struct Proxy {
+    kube: KubeConfigs,
+    relay: RelayArgs,
+    ephemeral: EphemeralArgs,
+    sidecar: SidecarArgs,
+    session: AgentSession,
+    shutdown: Shutdown
+}
+
+impl Proxy {
+    pub fn new(kube: KubeConfigs, relay: RelayArgs, ephemeral: EphemeralArgs, sidecar: SidecarArgs, agent: AgentArgs) -> Proxy {
+        let shutdown = Shutdown::new();
+        let session = AgentSession::new(agent.agent_dir, agent.agent_memfd, shutdown.clone());
+        Proxy {kube, relay, ephemeral, sidecar, session, shutdown}
+    }
+
+    pub async fn exec(&self, listen: Vec<SocketAddr>, target: AgentPoint) -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Cli::command();
+        let proxy = async {
+            let connector = open_connector(target, &self.kube, &self.session, &self.shutdown, &self.relay, &self.ephemeral, &self.sidecar).await
+                .map_err(|e| format!("Failed to start agent: {:?}", e))?;
+            let provider = TCPConnectionProvider::from_addresses(listen).listen_for_connections().await;
+            while let Ok((con, _)) = provider.accept().await {
+                let connector = connector.clone();
+                self.shutdown.spawn_connection(async move {
+                    if let Err(e) = proxy_connection(con, &connector).await {
+                        log::info!("HTTP proxy connection failed: {:?}", e);
+                    }
+                });
+            }
+            Ok::<(), String>(())
+        };
+        // agents, pods and containers of the session are cleaned up however it ends
+        if let Err(e) = self.shutdown.run_until_signal(proxy).await {
+            cmd.error(ErrorKind::Io, e).exit();
+        }
+        Ok(())
+    }
+}
+
+// Tunnels CONNECT requests and forwards plain requests through the agent, which resolves host names
+async fn proxy_connection(con: TcpStream, connector: &MuxConnector) -> Result<(), http::Error> {
+    let mut con = BufReader::new(con);
+    let req = match http::accept(&mut con).await {
+        Ok(req) => req,
+        Err(http::Error::BadRequest(e)) => {
+            http::respond(&mut con, 400, "Bad Request").await?;
+            return Err(http::Error::BadRequest(e));
+        },
+        Err(e) => return Err(e),
+    };
+    let mut stream = match dial(connector, &req.destination).await {
+        Ok(stream) => stream,
+        // no stream to the agent is not a failure of the destination
+        Err(status) if status == socks::GENERAL_FAILURE => return http::respond(&mut con, 503, "Service Unavailable").await,
+        Err(_) => return http::respond(&mut con, 502, "Bad Gateway").await,
+    };
+    if req.tunnel {
+        http::respond(&mut con, 200, "Connection established").await?;
+    } else {
+        stream.write_all(&req.head).await?;
+    }
+    connect(Box::new(con), Box::new(stream)).await;
+    Ok(())
+}
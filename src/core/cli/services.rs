@@ -0,0 +1,109 @@
+use std::{net::{SocketAddr, IpAddr, Ipv4Addr}, sync::Arc};
+
+use clap::{ErrorKind, CommandFactory};
+use futures::future::join_all;
+use tokio::net::UdpSocket;
+
+use crate::endpoint::{kube::KubeConfigs, AgentSession, connect, socket::{TCPConnectionProvider, TcpListeners}, dns::{self, Resolver}};
+use crate::mux::MuxConnector;
+use crate::shutdown::Shutdown;
+
+use super::{AgentPoint, Cli, RelayArgs, EphemeralArgs, SidecarArgs, AgentArgs};
+use super::socks::{open_connector, dial};
+
+static HOSTS_FILE: &str = "/etc/hosts";
+
+pub struct Services {
+    kube: KubeConfigs,
+    relay: RelayArgs,
+    ephemeral: EphemeralArgs,
+    sidecar: SidecarArgs,
+    session: AgentSession,
+    shutdown: Shutdown
+}
+
+impl Services {
+    pub fn new(kube: KubeConfigs, relay: RelayArgs, ephemeral: EphemeralArgs, sidecar: SidecarArgs, agent: AgentArgs) -> Services {
+        let shutdown = Shutdown::new();
+        let session = AgentSession::new(agent.agent_dir, agent.agent_memfd, shutdown.clone());
+        Services {kube, relay, ephemeral, sidecar, session, shutdown}
+    }
+
+    pub async fn exec(&self, target: AgentPoint, services: Vec<(String, u16)>, dns: Option<SocketAddr>, hosts: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Cli::command();
+        let mut resolver = Resolver::new();
+        let mut listeners = vec![];
+        for (name, port) in services {
+            let addr = match resolver.allocate(&name) {
+                Ok(addr) => addr,
+                Err(e) => cmd.error(ErrorKind::ValueValidation, format!("Failed to allocate address for {}: {:?}", name, e)).exit(),
+            };
+            let local = SocketAddr::new(IpAddr::V4(addr), port);
+            let listener = TCPConnectionProvider::new(local).listen_for_connections().await;
+            listeners.push((format!("{}:{}", name, port), local, listener));
+        }
+        let resolver = Arc::new(resolver);
+
+        let forward = async {
+            let connector = open_connector(target, &self.kube, &self.session, &self.shutdown, &self.relay, &self.ephemeral, &self.sidecar).await
+                .map_err(|e| format!("Failed to start agent: {:?}", e))?;
+            if hosts {
+                let addrs: Vec<Ipv4Addr> = listeners.iter().filter_map(|(_, local, _)| match local.ip() {
+                    IpAddr::V4(addr) => Some(addr),
+                    _ => None,
+                }).collect();
+                add_hosts_entries(&resolver, addrs, &self.shutdown).await?;
+            }
+            if let Some(dns) = dns {
+                let socket = UdpSocket::bind(dns).await.map_err(|e| format!("Failed to run DNS server on {}: {}", dns, e))?;
+                println!("DNS server listening on {}", dns);
+                tokio::spawn(dns::serve(socket, resolver.clone()));
+            }
+            let forwards = listeners.into_iter().map(|(destination, local, listener)| {
+                println!("{} -> {}", destination, local);
+                forward_service(listener, destination, connector.clone(), &self.shutdown)
+            });
+            join_all(forwards).await;
+            Ok::<(), String>(())
+        };
+        // agents, pods and containers of the session are cleaned up however it ends
+        if let Err(e) = self.shutdown.run_until_signal(forward).await {
+            cmd.error(ErrorKind::Io, e).exit();
+        }
+        Ok(())
+    }
+}
+
+async fn forward_service(listener: TcpListeners, destination: String, connector: MuxConnector, shutdown: &Shutdown) {
+    while let Ok((con, _)) = listener.accept().await {
+        let connector = connector.clone();
+        let destination = destination.clone();
+        shutdown.spawn_connection(async move {
+            match dial(&connector, &destination).await {
+                Ok(stream) => _ = connect(Box::new(con), Box::new(stream)).await,
+                Err(status) => log::info!("Failed to connect to {}: reply {}", destination, status),
+            }
+        });
+    }
+}
+
+// Entries are marked with the process id and removed when the session ends
+async fn add_hosts_entries(resolver: &Resolver, mut addrs: Vec<Ipv4Addr>, shutdown: &Shutdown) -> Result<(), String> {
+    let marker = format!("# rs {}", std::process::id());
+    let mut hosts = tokio::fs::read_to_string(HOSTS_FILE).await.map_err(|e| format!("Failed to read {}: {}", HOSTS_FILE, e))?;
+    if !hosts.is_empty() && !hosts.ends_with('\n') {
+        hosts.push('\n');
+    }
+    addrs.sort();
+    addrs.dedup();
+    for addr in addrs {
+        hosts.push_str(&format!("{} {} {}\n", addr, resolver.names(addr).join(" "), marker));
+    }
+    tokio::fs::write(HOSTS_FILE, hosts).await.map_err(|e| format!("Failed to write {}: {}", HOSTS_FILE, e))?;
+    shutdown.register(format!("entries in {}", HOSTS_FILE), async move {
+        let hosts = tokio::fs::read_to_string(HOSTS_FILE).await.map_err(|e| e.to_string())?;
+        let hosts: String = hosts.lines().filter(|l| !l.ends_with(&marker)).map(|l| format!("{}\n", l)).collect();
+        tokio::fs::write(HOSTS_FILE, hosts).await.map_err(|e| e.to_string())
+    });
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use std::net::SocketAddr;
+
+use clap::{ErrorKind, CommandFactory};
+use tokio::{net::TcpStream, io::DuplexStream};
+
+use crate::endpoint::{kube::KubeConfigs, AgentSession, UNIX_DESTINATION_PREFIX, connect, socket::TCPConnectionProvider, docker::DockerEndpoint, socks};
+use crate::mux::MuxConnector;
+use crate::shutdown::Shutdown;
+
+use super::{AgentPoint, Cli, ForwardPoint, KubeForwardPoint, DockerForwardPoint, Port, RelayArgs, EphemeralArgs, SidecarArgs, AgentArgs};
+use super::pf::{Error, prepare_origin_endpoint, prepare_destination_endpoint};
+
+pub struct Socks {
+    kube: KubeConfigs,
+    relay: RelayArgs,
+    ephemeral: EphemeralArgs,
+    sidecar: SidecarArgs,
+    session: AgentSession,
+    shutdown: Shutdown
+}
+
+impl Socks {
+    pub fn new(kube: KubeConfigs, relay: RelayArgs, ephemeral: EphemeralArgs, sidecar: SidecarArgs, agent: AgentArgs) -> Socks {
+        let shutdown = Shutdown::new();
+        let session = AgentSession::new(agent.agent_dir, agent.agent_memfd, shutdown.clone());
+        Socks {kube, relay, ephemeral, sidecar, session, shutdown}
+    }
+
+    pub async fn exec(&self, listen: Vec<SocketAddr>, target: AgentPoint) -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Cli::command();
+        let proxy = async {
+            let connector = open_connector(target, &self.kube, &self.session, &self.shutdown, &self.relay, &self.ephemeral, &self.sidecar).await
+                .map_err(|e| format!("Failed to start agent: {:?}", e))?;
+            let provider = TCPConnectionProvider::from_addresses(listen).listen_for_connections().await;
+            while let Ok((con, _)) = provider.accept().await {
+                let connector = connector.clone();
+                self.shutdown.spawn_connection(async move {
+                    if let Err(e) = proxy_connection(con, &connector).await {
+                        log::info!("SOCKS connection failed: {:?}", e);
+                    }
+                });
+            }
+            Ok::<(), String>(())
+        };
+        // agents, pods and containers of the session are cleaned up however it ends
+        if let Err(e) = self.shutdown.run_until_signal(proxy).await {
+            cmd.error(ErrorKind::Io, e).exit();
+        }
+        Ok(())
+    }
+}
+
+// Negotiates with the client and connects it through the agent, which resolves host names
+async fn proxy_connection(mut con: TcpStream, connector: &MuxConnector) -> Result<(), socks::Error> {
+    let destination = socks::accept(&mut con).await?;
+    match dial(connector, &destination).await {
+        Ok(stream) => {
+            socks::reply(&mut con, socks::SUCCEEDED).await?;
+            connect(Box::new(con), Box::new(stream)).await;
+            Ok(())
+        },
+        Err(status) => socks::reply(&mut con, status).await,
+    }
+}
+
+// Opens connection to host:port of a client through the agent, unix sockets are only reached by forward points
+pub(super) async fn dial(connector: &MuxConnector, destination: &str) -> Result<DuplexStream, u8> {
+    if destination.starts_with(UNIX_DESTINATION_PREFIX) {
+        return Err(socks::ADDRESS_NOT_SUPPORTED);
+    }
+    socks::dial(connector, destination).await
+}
+
+// Installs agent into the target and starts it, port of forward points is not used by the agent
+pub(super) async fn open_connector(target: AgentPoint, kube: &KubeConfigs, session: &AgentSession, shutdown: &Shutdown, relay: &RelayArgs, ephemeral: &EphemeralArgs, sidecar: &SidecarArgs) -> Result<MuxConnector, Error> {
+    let point = match target {
+        AgentPoint::Kube{context, namespace, pod: None} => {
+            let k = KubeForwardPoint{context, namespace, pod: None, host: None, container: None, port: Port::Tcp(0)};
+            prepare_destination_endpoint(ForwardPoint::Kube(k), kube, session, shutdown, relay, ephemeral, sidecar).await?
+        },
+        AgentPoint::Kube{context, namespace, pod} => {
+            let k = KubeForwardPoint{context, namespace, pod, host: None, container: None, port: Port::Tcp(0)};
+            prepare_origin_endpoint(ForwardPoint::Kube(k), kube, session, shutdown, ephemeral, sidecar).await?
+        },
+        AgentPoint::Docker(container) => {
+            let d = DockerForwardPoint{container, port: Port::Tcp(0)};
+            prepare_origin_endpoint(ForwardPoint::Docker(d), kube, session, shutdown, ephemeral, sidecar).await?
+        },
+    };
+    match point {
+        ForwardPoint::Kube(KubeForwardPoint{context, namespace, pod: Some(pod), container, ..}) => {
+            Ok(kube.open_connections(context, namespace, pod, container, session, &["--dynamic"]).await?)
+        },
+        ForwardPoint::Docker(DockerForwardPoint{container, ..}) => {
+            let doc = DockerEndpoint::new();
+            Ok(doc.open_connections(&container, session, &["--dynamic"]).await?)
+        },
+        _ => Err(Error::Kube(crate::endpoint::kube::Error::RelayError("Relay pod was not created".to_string()))),
+    }
+}
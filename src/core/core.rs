@@ -1,3 +1,5 @@
 pub mod endpoint;
 pub mod cli;
-pub mod mux;
\ No newline at end of file
+pub mod mux;
+pub mod handshake;
+pub mod shutdown;
\ No newline at end of file
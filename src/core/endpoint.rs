@@ -1,10 +1,19 @@
 
+use std::{collections::HashMap, future::Future, net::SocketAddr, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
 use bytes::BytesMut;
 use futures::future::join_all;
 use tokio::io::{AsyncWrite, AsyncRead, copy, split, AsyncReadExt, AsyncWriteExt};
-use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::mpsc::{channel, unbounded_channel, Receiver, UnboundedSender};
 use tokio::sync::oneshot;
 use tokio::{task, select};
+use tokio::time::{Instant, sleep_until};
+use tokio_util::sync::CancellationToken;
+use crate::mux::MuxConnector;
+use crate::shutdown::Shutdown;
+use shaping::RateLimiter;
+
+static TAP_BUFFER_SIZE: usize = 8 * 1024;
 
 pub trait PipeEndpoint: Send + Unpin {
     fn get_sink_and_source(self: Box<Self>) -> (Box<dyn AsyncRead + Unpin + Send + 'static>, Box<dyn AsyncWrite + Unpin + Send + 'static>);
@@ -36,12 +45,13 @@ impl PipeCopySource {
 }
 
 
-// Returns message stream with progress
-pub async fn pipe_copy(src: PipeCopySource, dst: PipeCopyDestination) -> Receiver<usize> {
+// Returns message stream with progress, limit is in bytes per second
+pub async fn pipe_copy(src: PipeCopySource, dst: PipeCopyDestination, limit: Option<u64>) -> Receiver<usize> {
     let (rx, tx) = channel::<usize>(100);
     task::spawn(async move {
         let mut from = src.reader;
         let mut to = dst.writer;
+        let mut limiter = limit.map(RateLimiter::new);
         let mut buf = BytesMut::with_capacity(1024*10);
         loop {
             buf.clear();
@@ -50,6 +60,9 @@ pub async fn pipe_copy(src: PipeCopySource, dst: PipeCopyDestination) -> Receive
                     if buf_size == 0 {
                         break; 
                     }
+                    if let Some(limiter) = &mut limiter {
+                        limiter.wait(buf_size).await;
+                    }
                     match to.write_all(&mut buf).await {
                         Ok(_) => {},
                         Err(_) => {
@@ -69,16 +82,120 @@ pub async fn pipe_copy(src: PipeCopySource, dst: PipeCopyDestination) -> Receive
 
 
 
-pub async fn connect(from: Box<dyn PipeEndpoint>, to: Box<dyn PipeEndpoint>) -> u64
+// Observes bytes `connect` copies between endpoints, e.g. to print or record them
+pub trait Tap: Send + Sync {
+    // Called for each connection before anything is copied
+    fn open(&self, addresses: Option<ConnectionAddresses>) -> Box<dyn ConnectionTap>;
+    // Output of the tap, flushed when the session ends
+    fn writer(&self) -> &TapWriter;
+}
+
+enum TapRecord {
+    Bytes(Vec<u8>),
+    Flush(oneshot::Sender<()>)
+}
+
+// Writes output of a tap in order on a single task, copying never waits for files or stderr
+#[derive(Clone)]
+pub struct TapWriter {
+    records: UnboundedSender<TapRecord>
+}
+
+impl TapWriter {
+    pub fn spawn(mut out: impl AsyncWrite + Unpin + Send + 'static) -> Self {
+        let (records, mut pending) = unbounded_channel();
+        task::spawn(async move {
+            while let Some(record) = pending.recv().await {
+                match record {
+                    TapRecord::Bytes(bytes) => if let Err(e) = out.write_all(&bytes).await {
+                        log::error!("Failed to write traffic: {}", e);
+                    },
+                    TapRecord::Flush(done) => {
+                        _ = out.flush().await;
+                        _ = done.send(());
+                    },
+                }
+            }
+        });
+        TapWriter { records }
+    }
+
+    pub fn write(&self, bytes: Vec<u8>) {
+        _ = self.records.send(TapRecord::Bytes(bytes));
+    }
+
+    // Resolves once everything written before is written out
+    pub async fn flush(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.records.send(TapRecord::Flush(done_tx)).is_ok() {
+            _ = done_rx.await;
+        }
+    }
+}
+
+// Addresses of a connection accepted on a local TCP port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionAddresses {
+    pub peer: SocketAddr,
+    pub local: SocketAddr
+}
+
+pub trait ConnectionTap: Send {
+    fn data(&mut self, direction: Direction, bytes: &[u8]);
+    fn close(&mut self);
+}
+
+// Taps of a connection shared by both directions
+type ConnectionTaps = Arc<Mutex<Vec<Box<dyn ConnectionTap>>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // from the first endpoint passed to `connect` to the second one
+    Outbound,
+    Inbound
+}
+
+// What `connect` does besides copying
+#[derive(Clone, Default)]
+pub struct ConnectOptions {
+    pub taps: Vec<Arc<dyn Tap>>,
+    pub addresses: Option<ConnectionAddresses>,
+    // connection is closed when nothing is copied either way for this long
+    pub idle_timeout: Option<Duration>,
+    pub lifetime: Option<Duration>
+}
+
+pub async fn connect(from: Box<dyn PipeEndpoint>, to: Box<dyn PipeEndpoint>) -> u64 {
+    connect_with(from, to, ConnectOptions::default()).await
+}
+
+pub async fn connect_with(from: Box<dyn PipeEndpoint>, to: Box<dyn PipeEndpoint>, options: ConnectOptions) -> u64
 {
     let (mut source1, mut sink1) = Box::new(from).get_sink_and_source();
     let (mut source2, mut sink2) = Box::new(to).get_sink_and_source();
     let (kill1, end1) = oneshot::channel::<bool>();
     let (kill2, end2) = oneshot::channel::<bool>();
+    let taps = match options.taps.is_empty() {
+        true => None,
+        false => Some(Arc::new(Mutex::new(options.taps.iter().map(|t| t.open(options.addresses)).collect::<Vec<_>>()))),
+    };
+    let taps1 = taps.clone();
+    let taps2 = taps.clone();
+    let activity = options.idle_timeout.map(|_| Activity::new());
+    let activity1 = activity.clone();
+    let activity2 = activity.clone();
+    // closes both directions when the connection is idle or too old
+    let stop = CancellationToken::new();
+    let stop1 = stop.clone();
+    let stop2 = stop.clone();
+    let watchdog = match (options.idle_timeout, options.lifetime) {
+        (None, None) => None,
+        (idle_timeout, lifetime) => Some(task::spawn(watch(activity, idle_timeout, lifetime, stop))),
+    };
     
     let t1 = task::spawn(async move {
         select! {
-            size = copy(&mut source1, &mut sink2) => {
+            size = copy_tapped(&mut source1, &mut sink2, Direction::Outbound, taps1, activity1) => {
                 _ = kill2.send(true);
                 return match size {
                     Ok(s) => s,
@@ -88,12 +205,14 @@ pub async fn connect(from: Box<dyn PipeEndpoint>, to: Box<dyn PipeEndpoint>) ->
             _ = end1 => {
                 return 0;
             }
-        };
+            // closed by the watchdog
+            _ = stop1.cancelled() => 0,
+        }
     });
     
     let t2 = task::spawn(async move {
         select! {
-            size = copy(&mut source2, &mut sink1) => {
+            size = copy_tapped(&mut source2, &mut sink1, Direction::Inbound, taps2, activity2) => {
                 _ = kill1.send(true);
                 return match size {
                     Ok(s) => s,
@@ -103,10 +222,18 @@ pub async fn connect(from: Box<dyn PipeEndpoint>, to: Box<dyn PipeEndpoint>) ->
             _ = end2 => {
                 return 0;
             }
-        };
+            // closed by the watchdog
+            _ = stop2.cancelled() => 0,
+        }
     });
 
     let res = join_all(vec![t1, t2]).await;
+    if let Some(watchdog) = watchdog {
+        watchdog.abort();
+    }
+    if let Some(taps) = taps {
+        taps.lock().unwrap().iter_mut().for_each(|t| t.close());
+    }
     let size1 = match res[0] {
         Ok(s) => s,
         Err(_) => 0,  
@@ -121,6 +248,73 @@ pub async fn connect(from: Box<dyn PipeEndpoint>, to: Box<dyn PipeEndpoint>) ->
     size2.clone()
 }
 
+// Last time anything was copied in either direction
+#[derive(Clone)]
+struct Activity {
+    start: Instant,
+    // milliseconds since start
+    last: Arc<AtomicU64>
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity { start: Instant::now(), last: Arc::new(AtomicU64::new(0)) }
+    }
+
+    fn touch(&self) {
+        self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+async fn watch(activity: Option<Activity>, idle_timeout: Option<Duration>, lifetime: Option<Duration>, stop: CancellationToken) {
+    let end = lifetime.map(|l| Instant::now() + l);
+    loop {
+        let idle_end = activity.as_ref().zip(idle_timeout).map(|(a, t)| a.last() + t);
+        match [end, idle_end].into_iter().flatten().min() {
+            Some(at) => sleep_until(at).await,
+            None => return,
+        }
+        if end.map(|e| e <= Instant::now()).unwrap_or_default() {
+            log::info!("Closing connection open for {:?}", lifetime.unwrap_or_default());
+            break;
+        }
+        // bytes copied while sleeping move the end
+        if activity.as_ref().zip(idle_timeout).map(|(a, t)| a.last() + t <= Instant::now()).unwrap_or_default() {
+            log::info!("Closing connection idle for {:?}", idle_timeout.unwrap_or_default());
+            break;
+        }
+    }
+    stop.cancel();
+}
+
+// Same as `copy`, passing each chunk to the taps before it is written
+async fn copy_tapped(source: &mut (impl AsyncRead + Unpin), sink: &mut (impl AsyncWrite + Unpin), direction: Direction, taps: Option<ConnectionTaps>, activity: Option<Activity>) -> std::io::Result<u64> {
+    if taps.is_none() && activity.is_none() {
+        return copy(source, sink).await;
+    }
+    let mut buf = vec![0u8; TAP_BUFFER_SIZE];
+    let mut size = 0;
+    loop {
+        let len = source.read(&mut buf).await?;
+        if let Some(activity) = &activity {
+            activity.touch();
+        }
+        if len == 0 {
+            sink.flush().await?;
+            return Ok(size);
+        }
+        if let Some(taps) = &taps {
+            taps.lock().unwrap().iter_mut().for_each(|t| t.data(direction, &buf[..len]));
+        }
+        sink.write_all(&buf[..len]).await?;
+        size += len as u64;
+    }
+}
+
 impl<T> PipeEndpoint for T 
     where T : AsyncRead + AsyncWrite + Unpin + Send + 'static
 {
@@ -130,9 +324,259 @@ impl<T> PipeEndpoint for T
     }
 }
 
-pub static AGENT: &[u8] = include_bytes!("../../target/release/agent");
-pub static AGENT_PATH: &str = "/tmp/rs-agent";
-pub static AGENT_KILL_PATH: &str = "/tmp/rs-agent.kill";
+#[cfg(feature = "agent-x86_64")]
+static AGENT_X86_64: &[u8] = include_bytes!("../../target/x86_64-unknown-linux-musl/release/agent");
+#[cfg(feature = "agent-aarch64")]
+static AGENT_AARCH64: &[u8] = include_bytes!("../../target/aarch64-unknown-linux-musl/release/agent");
+#[cfg(not(any(feature = "agent-x86_64", feature = "agent-aarch64")))]
+static AGENT_HOST: &[u8] = include_bytes!("../../target/release/agent");
+
+// Returns embedded agent matching `uname -m` output
+pub fn get_agent(arch: &str) -> Option<&'static [u8]> {
+    let arch = match arch.trim() {
+        "amd64" => "x86_64",
+        "arm64" => "aarch64",
+        a => a
+    };
+    match arch {
+        #[cfg(feature = "agent-x86_64")]
+        "x86_64" => Some(AGENT_X86_64),
+        #[cfg(feature = "agent-aarch64")]
+        "aarch64" => Some(AGENT_AARCH64),
+        #[cfg(not(any(feature = "agent-x86_64", feature = "agent-aarch64")))]
+        a if a == std::env::consts::ARCH => Some(AGENT_HOST),
+        _ => None
+    }
+}
+
+pub fn get_agent_architectures() -> Vec<&'static str> {
+    let mut archs: Vec<&str> = ["x86_64", "aarch64", std::env::consts::ARCH]
+        .into_iter()
+        .filter(|arch| get_agent(arch).is_some())
+        .collect();
+    archs.sort();
+    archs.dedup();
+    archs
+}
+
+// Hex encoded sha256 of the agent, same as `sha256sum` prints
+pub fn agent_checksum(agent: &[u8]) -> String {
+    openssl::sha::sha256(agent).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Compares `sha256sum <file>` output with the agent
+pub fn agent_checksum_matches(sha256sum: &str, agent: &[u8]) -> bool {
+    sha256sum.split_whitespace().next() == Some(agent_checksum(agent).as_str())
+}
+
+// Uploads agent into the directory unless already there and returns path of the session copy.
+// `exec` runs a command in the pod or container, writes the input to its stdin and returns its stdout
+pub async fn install_agent_into<F, R>(exec: F, dir: &str, agent: &'static [u8], session: &AgentSession) -> Result<String, String>
+    where F: Fn(Vec<String>, Option<&'static [u8]>) -> R, R: Future<Output = Result<String, String>>
+{
+    let cmd = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    let cache = session.get_cache_path(dir);
+    // skip upload of already installed agent
+    let installed = exec(cmd(&["sha256sum", &cache]), None).await
+        .map(|sum| agent_checksum_matches(&sum, agent))
+        .unwrap_or(false);
+    if installed {
+        log::info!("Agent already installed in {}", dir);
+    } else {
+        // renamed over the shared agent once complete, so other sessions never copy a partial upload
+        let upload = session.get_upload_path(dir);
+        let uploaded = async {
+            exec(cmd(&["dd", &format!("of={}", upload)]), Some(agent)).await?;
+            verify_agent(&exec, &upload, agent).await?;
+            exec(cmd(&["chmod", "+x", &upload]), None).await?;
+            exec(cmd(&["mv", "-f", &upload, &cache]), None).await
+        };
+        if let Err(e) = uploaded.await {
+            _ = exec(cmd(&["rm", "-f", &upload]), None).await;
+            return Err(e);
+        }
+    }
+    // copy used only by this session, the shared agent could have been replaced by another build meanwhile
+    let path = session.get_agent_path(dir);
+    exec(cmd(&["cp", &cache, &path]), None).await?;
+    if let Err(e) = verify_agent(&exec, &path, agent).await {
+        _ = exec(cmd(&["rm", "-f", &path]), None).await;
+        return Err(e);
+    }
+    // fails on noexec mounts
+    exec(cmd(&[&path, "agent", "--help"]), None).await?;
+    Ok(path)
+}
+
+// Compares the file with the agent by checksum, or by size when there is no `sha256sum`
+async fn verify_agent<F, R>(exec: &F, path: &str, agent: &'static [u8]) -> Result<(), String>
+    where F: Fn(Vec<String>, Option<&'static [u8]>) -> R, R: Future<Output = Result<String, String>>
+{
+    if let Ok(sum) = exec(vec!["sha256sum".to_string(), path.to_string()], None).await {
+        return match agent_checksum_matches(&sum, agent) {
+            true => Ok(()),
+            false => Err(format!("Checksum of {} does not match the agent", path)),
+        };
+    }
+    let size = exec(vec!["wc".to_string(), "-c".to_string(), path.to_string()], None).await?;
+    match size.split_whitespace().next() == Some(agent.len().to_string().as_str()) {
+        true => Ok(()),
+        false => Err(format!("Size of {} does not match the agent", path)),
+    }
+}
+
+pub fn unsupported_architecture_message(arch: &str) -> String {
+    format!("No embedded agent for '{}' architecture, available: {}", arch.trim(), get_agent_architectures().join(", "))
+}
+
+// Installed agent shared by sessions, each session runs its own copy of it
+pub static AGENT_NAME: &str = "rs-agent";
+
+// Directories tried in order after the one requested, before the emptyDir mounts and $HOME
+pub static AGENT_DIRS: [&str; 2] = ["/tmp", "/dev/shm"];
+
+// Reads agent of the given size from stdin into a memfd and runs it with the remaining arguments
+static MEMFD_LOADER: &str = "import os,sys
+n=int(sys.argv[1])
+fd=os.memfd_create('rs-agent',0)
+while n>0:
+ b=os.read(0,min(n,65536))
+ if not b: sys.exit(1)
+ n-=os.write(fd,b)
+os.execv('/proc/self/fd/%d'%fd,['rs-agent']+sys.argv[2:])";
+
+// Command checking that agent could be run from memory
+pub static MEMFD_CHECK: [&str; 3] = ["python3", "-c", "import os; os.memfd_create"];
+
+// Where the agent of the session is installed in a pod or container
+#[derive(Debug, Clone)]
+pub enum AgentLocation {
+    Path(String),
+    // uploaded through stdin on each run, so connections share a single run
+    Memfd(&'static [u8])
+}
+
+// Dynamic destinations of unix sockets, TCP ones are host:port
+pub static UNIX_DESTINATION_PREFIX: &str = "unix:";
+
+// Destination the agent started with the arguments connects to, e.g. `-p 80 --host web` is web:80
+pub fn dynamic_destination(args: &[&str]) -> Option<String> {
+    match args {
+        ["--unix", path] => Some(format!("{}{}", UNIX_DESTINATION_PREFIX, path)),
+        ["-p", port, "--host", host] if host.contains(':') => Some(format!("[{}]:{}", host, port)),
+        ["-p", port, "--host", host] => Some(format!("{}:{}", host, port)),
+        ["-p", port] => Some(format!("localhost:{}", port)),
+        _ => None,
+    }
+}
+
+// Command running the agent and data written to its stdin before anything else
+pub struct AgentCommand {
+    pub cmd: Vec<String>,
+    pub preload: Option<&'static [u8]>
+}
+
+// Agent copies owned by a single `rs` process
+#[derive(Clone)]
+pub struct AgentSession {
+    id: String,
+    dir: Option<String>,
+    memfd: bool,
+    locations: Arc<Mutex<HashMap<String, AgentLocation>>>,
+    // agents connecting to destinations, started once per target
+    connectors: Arc<tokio::sync::Mutex<HashMap<String, MuxConnector>>>,
+    shutdown: Shutdown
+}
+
+impl AgentSession {
+    pub fn new(dir: Option<String>, memfd: bool, shutdown: Shutdown) -> Self {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        AgentSession { 
+            id: format!("{:x}-{:x}", std::process::id(), started),
+            dir,
+            memfd,
+            locations: Arc::new(Mutex::new(HashMap::new())),
+            connectors: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            shutdown
+        }
+    }
+
+    // Registers cleanup of agents and files the session left in pods and containers
+    pub fn on_shutdown(&self, description: String, task: impl Future<Output = Result<(), String>> + Send + 'static) {
+        self.shutdown.register(description, task);
+    }
+
+    // Requested directory first, then defaults, mounts and home directory
+    pub fn get_agent_dirs(&self, mounts: Vec<String>, home: Option<String>) -> Vec<String> {
+        let candidates = self.dir.iter().cloned()
+            .chain(AGENT_DIRS.iter().map(|d| d.to_string()))
+            .chain(mounts)
+            .chain(home);
+        let mut dirs: Vec<String> = vec![];
+        for dir in candidates {
+            let dir = dir.trim().trim_end_matches('/').to_string();
+            if !dir.is_empty() && !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+        dirs
+    }
+
+    pub fn prefers_memfd(&self) -> bool {
+        self.memfd
+    }
+
+    // Agent shared by sessions installing into the directory
+    pub fn get_cache_path(&self, dir: &str) -> String {
+        format!("{}/{}", dir, AGENT_NAME)
+    }
+
+    // Upload in progress, renamed to the cache path when complete
+    pub fn get_upload_path(&self, dir: &str) -> String {
+        format!("{}/{}.{}.tmp", dir, AGENT_NAME, self.id)
+    }
+
+    pub fn get_agent_path(&self, dir: &str) -> String {
+        format!("{}/{}-{}", dir, AGENT_NAME, self.id)
+    }
+
+    pub fn set_location(&self, target: String, location: AgentLocation) {
+        self.locations.lock().unwrap().insert(target, location);
+    }
+
+    pub fn runs_from_memory(&self, target: &str) -> bool {
+        matches!(self.locations.lock().unwrap().get(target), Some(AgentLocation::Memfd(_)))
+    }
+
+    // Agent of the target connecting to destinations, started when there is none or it exited
+    pub async fn get_connector<E>(&self, target: &str, start: impl Future<Output = Result<MuxConnector, E>>) -> Result<MuxConnector, E> {
+        let mut connectors = self.connectors.lock().await;
+        match connectors.get(target) {
+            Some(connector) if !connector.is_closed() => Ok(connector.clone()),
+            _ => {
+                let connector = start.await?;
+                connectors.insert(target.to_string(), connector.clone());
+                Ok(connector)
+            },
+        }
+    }
+
+    // Command running agent of the session installed into the target
+    pub fn get_agent_command(&self, target: &str, args: &[&str]) -> Option<AgentCommand> {
+        let location = self.locations.lock().unwrap().get(target).cloned()?;
+        let args = args.iter().map(|a| a.to_string());
+        Some(match location {
+            AgentLocation::Path(path) => AgentCommand {
+                cmd: vec![path, "agent".to_string()].into_iter().chain(args).collect(),
+                preload: None
+            },
+            AgentLocation::Memfd(agent) => AgentCommand {
+                cmd: vec!["python3".to_string(), "-c".to_string(), MEMFD_LOADER.to_string(), agent.len().to_string(), "agent".to_string()].into_iter().chain(args).collect(),
+                preload: Some(agent)
+            },
+        })
+    }
+}
 
 pub mod stdio;
 
@@ -141,3 +585,135 @@ pub mod socket;
 pub mod kube;
 
 pub mod docker;
+
+pub mod socks;
+
+pub mod http;
+
+pub mod dns;
+
+pub mod tls;
+
+pub mod inspect;
+
+pub mod pcap;
+
+pub mod shaping;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+    use tokio::time::{Instant, timeout};
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use super::{agent_checksum, agent_checksum_matches, dynamic_destination, get_agent, install_agent_into, AgentSession, ConnectOptions, connect_with};
+    use crate::shutdown::Shutdown;
+
+    #[test]
+    fn agent_checksum_matches_sha256sum_output() {
+        let sum = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  /tmp/rs-agent\n";
+        assert!(agent_checksum_matches(sum, b""));
+        assert!(!agent_checksum_matches(sum, b"agent"));
+        assert!(!agent_checksum_matches("", b""));
+    }
+
+    #[test]
+    fn agent_arguments_as_dynamic_destinations() {
+        assert_eq!(dynamic_destination(&["-p", "5432", "--host", "db.local"]).as_deref(), Some("db.local:5432"));
+        assert_eq!(dynamic_destination(&["-p", "5432", "--host", "fd00::1"]).as_deref(), Some("[fd00::1]:5432"));
+        assert_eq!(dynamic_destination(&["-p", "80"]).as_deref(), Some("localhost:80"));
+        assert_eq!(dynamic_destination(&["--unix", "/run/app.sock"]).as_deref(), Some("unix:/run/app.sock"));
+        assert_eq!(dynamic_destination(&["-l", "-p", "80"]), None);
+    }
+
+    #[test]
+    fn agent_architecture_aliases_work() {
+        assert!(get_agent(std::env::consts::ARCH).is_some());
+        assert!(get_agent(&format!("{}\n", std::env::consts::ARCH)).is_some());
+        assert!(get_agent("mips").is_none());
+    }
+
+    #[tokio::test]
+    async fn connect_closes_idle_connections() {
+        let (from, mut client) = duplex(64);
+        let (to, mut server) = duplex(64);
+        let options = ConnectOptions { idle_timeout: Some(Duration::from_millis(100)), ..Default::default() };
+        let start = Instant::now();
+        let forward = tokio::spawn(connect_with(Box::new(from), Box::new(to), options));
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        timeout(Duration::from_secs(2), forward).await.unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    // Files of a container, `cp` truncates copies when `truncate_copies` is set
+    fn fake_exec(files: Arc<Mutex<HashMap<String, Vec<u8>>>>, truncate_copies: bool) -> impl Fn(Vec<String>, Option<&'static [u8]>) -> std::future::Ready<Result<String, String>> {
+        move |cmd, input| {
+            let mut files = files.lock().unwrap();
+            let args: Vec<&str> = cmd.iter().map(String::as_str).collect();
+            let missing = |path: &str| Err(format!("{}: no such file", path));
+            std::future::ready(match args[..] {
+                ["sha256sum", path] => match files.get(path) {
+                    Some(file) => Ok(format!("{}  {}\n", agent_checksum(file), path)),
+                    None => missing(path),
+                },
+                ["dd", of] => {
+                    files.insert(of.trim_start_matches("of=").to_string(), input.unwrap_or_default().to_vec());
+                    Ok(String::new())
+                },
+                ["chmod", "+x", path] if !files.contains_key(path) => missing(path),
+                ["chmod", "+x", _] => Ok(String::new()),
+                ["rm", "-f", path] => {
+                    files.remove(path);
+                    Ok(String::new())
+                },
+                ["mv", "-f", from, to] | ["cp", from, to] => match files.get(from).cloned() {
+                    Some(mut file) => {
+                        if args[0] == "mv" {
+                            files.remove(from);
+                        } else if truncate_copies {
+                            file.truncate(1);
+                        }
+                        files.insert(to.to_string(), file);
+                        Ok(String::new())
+                    },
+                    None => missing(from),
+                },
+                [path, "agent", "--help"] if files.contains_key(path) => Ok(String::new()),
+                _ => Err(format!("{} failed", args[0])),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn agent_is_uploaded_renamed_and_copied() {
+        static AGENT: &[u8] = b"agent build";
+        let session = AgentSession::new(None, false, Shutdown::new());
+        let files = Arc::new(Mutex::new(HashMap::from([("/tmp/rs-agent".to_string(), b"agent bu".to_vec())])));
+        let path = install_agent_into(fake_exec(files.clone(), false), "/tmp", AGENT, &session).await.unwrap();
+        {
+            let files = files.lock().unwrap();
+            assert_eq!(files["/tmp/rs-agent"], AGENT);
+            assert_eq!(files[&path], AGENT);
+            assert!(!files.contains_key(&session.get_upload_path("/tmp")));
+        }
+
+        let path = session.get_agent_path("/tmp");
+        files.lock().unwrap().remove(&path);
+        assert!(install_agent_into(fake_exec(files.clone(), true), "/tmp", AGENT, &session).await.is_err());
+        assert!(!files.lock().unwrap().contains_key(&path));
+    }
+
+    #[test]
+    fn agent_dirs_start_with_requested_one() {
+        let session = AgentSession::new(Some("/data/".to_string()), false, Shutdown::new());
+        let dirs = session.get_agent_dirs(vec!["/cache".to_string(), "/tmp".to_string()], Some("/root\n".to_string()));
+        assert_eq!(dirs, vec!["/data", "/tmp", "/dev/shm", "/cache", "/root"]);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{collections::HashMap, net::Ipv4Addr, sync::Arc};
+
+use tokio::net::UdpSocket;
+
+// Loopback addresses handed out to forwarded names start here, 127.0.0.0/16 is left alone
+static FIRST_ADDRESS: Ipv4Addr = Ipv4Addr::new(127, 1, 0, 1);
+static LAST_ADDRESS: Ipv4Addr = Ipv4Addr::new(127, 255, 255, 254);
+// Seconds answers may be cached, addresses only live as long as the session
+static TTL: u32 = 5;
+static CLUSTER_DOMAIN: &str = "svc.cluster.local";
+
+static HEADER_SIZE: usize = 12;
+static TYPE_A: u16 = 1;
+static NXDOMAIN: u8 = 3;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    AddressesExhausted
+}
+
+// Names of forwarded services and loopback addresses allocated for them
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    names: HashMap<String, Ipv4Addr>,
+    next: u32
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Resolver { names: HashMap::new(), next: u32::from(FIRST_ADDRESS) }
+    }
+
+    // Returns address of the name, allocating one on first use. Kubernetes service names
+    // also resolve in their other forms, <svc>.<ns>, <svc>.<ns>.svc and <svc>.<ns>.svc.cluster.local
+    pub fn allocate(&mut self, name: &str) -> Result<Ipv4Addr, Error> {
+        let name = normalize(name);
+        if let Some(addr) = self.names.get(&name) {
+            return Ok(*addr);
+        }
+        if self.next > u32::from(LAST_ADDRESS) {
+            return Err(Error::AddressesExhausted);
+        }
+        let addr = Ipv4Addr::from(self.next);
+        self.next += 1;
+        for alias in aliases(&name) {
+            self.names.entry(alias).or_insert(addr);
+        }
+        Ok(addr)
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<Ipv4Addr> {
+        self.names.get(&normalize(name)).copied()
+    }
+
+    // Names resolving to the address
+    pub fn names(&self, addr: Ipv4Addr) -> Vec<String> {
+        let mut names: Vec<String> = self.names.iter().filter(|(_, a)| **a == addr).map(|(n, _)| n.clone()).collect();
+        names.sort_by_key(|n| n.len());
+        names
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn aliases(name: &str) -> Vec<String> {
+    let base = name.strip_suffix(&format!(".{}", CLUSTER_DOMAIN))
+        .or(name.strip_suffix(".svc"))
+        .unwrap_or(name);
+    match base.split('.').count() {
+        2 => vec![base.to_string(), format!("{}.svc", base), format!("{}.{}", base, CLUSTER_DOMAIN)],
+        _ => vec![name.to_string()],
+    }
+}
+
+// Answers A queries for allocated names over UDP, other names are answered with NXDOMAIN
+pub async fn serve(socket: UdpSocket, resolver: Arc<Resolver>) {
+    let mut buf = [0u8; 512];
+    loop {
+        let (size, peer) = match socket.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                log::error!("DNS server failed: {}", e);
+                return;
+            }
+        };
+        if let Some(response) = answer(&buf[..size], &resolver) {
+            _ = socket.send_to(&response, peer).await;
+        }
+    }
+}
+
+fn answer(query: &[u8], resolver: &Resolver) -> Option<Vec<u8>> {
+    // only queries with a single question are answered
+    if query.len() < HEADER_SIZE || query[2] & 0x80 != 0 || query[4..6] != [0, 1] {
+        return None;
+    }
+    let mut labels = vec![];
+    let mut pos = HEADER_SIZE;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        labels.push(String::from_utf8_lossy(query.get(pos..pos + len)?).to_string());
+        pos += len;
+    }
+    let qtype = u16::from_be_bytes([*query.get(pos)?, *query.get(pos + 1)?]);
+    let question = query.get(..pos + 4)?;
+
+    let addr = resolver.lookup(&labels.join("."));
+    let mut response = question.to_vec();
+    // response, authoritative, opcode and recursion desired copied from the query
+    response[2] = 0x80 | 0x04 | (query[2] & 0x79);
+    response[3] = if addr.is_some() { 0 } else { NXDOMAIN };
+    response[6..HEADER_SIZE].fill(0);
+    if let (Some(addr), true) = (addr, qtype == TYPE_A) {
+        response[7] = 1;
+        // name is a pointer to the question
+        response.extend_from_slice(&[0xC0, HEADER_SIZE as u8, 0, 1, 0, 1]);
+        response.extend_from_slice(&TTL.to_be_bytes());
+        response.extend_from_slice(&[0, 4]);
+        response.extend_from_slice(&addr.octets());
+    }
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.extend_from_slice(&[0, 0, 1, 0, 1]);
+        query
+    }
+
+    #[test]
+    fn service_names_resolve_in_all_forms() {
+        let mut resolver = Resolver::new();
+        let orders = resolver.allocate("orders.payments.svc.cluster.local").unwrap();
+        assert_eq!(orders, Ipv4Addr::new(127, 1, 0, 1));
+        assert_eq!(resolver.allocate("orders.payments").unwrap(), orders);
+        assert_eq!(resolver.allocate("db.example.com").unwrap(), Ipv4Addr::new(127, 1, 0, 2));
+        assert_eq!(resolver.lookup("Orders.Payments.svc."), Some(orders));
+        assert_eq!(resolver.lookup("example.com"), None);
+
+        let response = answer(&query("orders.payments.svc"), &resolver).unwrap();
+        assert_eq!(&response[..4], &[0x12, 0x34, 0x85, 0]);
+        assert_eq!(&response[6..8], &[0, 1]);
+        assert_eq!(&response[response.len() - 4..], &orders.octets());
+
+        let response = answer(&query("billing.payments"), &resolver).unwrap();
+        assert_eq!(response[3], NXDOMAIN);
+        assert_eq!(&response[6..8], &[0, 0]);
+    }
+}
@@ -1,16 +1,29 @@
-use bollard::{Docker, container::{ListContainersOptions, LogOutput}, exec::{CreateExecOptions, StartExecResults}};
-use tokio::{sync::{mpsc::{Receiver}, oneshot}, io::{AsyncRead, AsyncWrite, duplex, stderr, AsyncWriteExt, split, copy}};
+use bollard::{Docker, container::{ListContainersOptions, LogOutput, Config, CreateContainerOptions, RemoveContainerOptions}, exec::{CreateExecOptions, StartExecResults}, image::CreateImageOptions, models::HostConfig};
+use tokio::{sync::{mpsc::{Receiver}, oneshot}, io::{AsyncRead, AsyncWrite, DuplexStream, duplex, stderr, AsyncWriteExt, split, copy}, time::sleep};
 use futures::StreamExt;
-use std::{default::Default, path::Path, str::FromStr};
+use std::{default::Default, path::Path, pin::Pin, str::FromStr, collections::HashMap, time::{Duration, SystemTime, UNIX_EPOCH}};
 
-use crate::mux::Multiplexer;
+use crate::{mux::{Multiplexer, MuxConnector}, handshake::{self, handshake, FEATURE_DYNAMIC_DESTINATIONS, FEATURE_PORT_TAGS}};
 
-use super::{AGENT, AGENT_PATH, AGENT_KILL_PATH, PipeCopySource, PipeCopyDestination};
+use super::{AgentSession, AgentLocation, AgentCommand, MEMFD_CHECK, PipeCopySource, PipeCopyDestination, get_agent, unsupported_architecture_message, install_agent_into, dynamic_destination, socks};
+
+// Sidecar container exits on its own after this many seconds
+static SIDECAR_LIFETIME: u64 = 24 * 60 * 60;
+// Agent exit is polled every 100ms after shutdown message
+static AGENT_SHUTDOWN_ATTEMPTS: u32 = 50;
 
 #[derive(Debug, Clone)]
 pub enum Error {
     FailedToInitDocker,
-    DockerError(String)
+    DockerError(String),
+    UnsupportedArchitecture(String),
+    HandshakeError(handshake::Error)
+}
+
+impl From<handshake::Error> for Error {
+    fn from(e: handshake::Error) -> Self {
+        Error::HandshakeError(e)
+    }
 }
 
 
@@ -83,34 +96,82 @@ impl DockerEndpoint {
         Ok(out.split("\n").map(|s|{s.to_string()}).collect::<Vec<String>>())
     }
 
-    pub async fn install_agent(&self, container_name: &String) -> Result<(), Error> {
+    pub async fn install_agent(&self, container_name: &str, session: &AgentSession) -> Result<(), Error> {
         let doc = match &self.docker {
             Ok(d) => d,
             Err(e) => return Err(e.clone())
         };
-        let config = CreateExecOptions::<String> {
-            attach_stdin: Some(true),
-            cmd: Some(vec!["dd".to_string(), format!("of={}", AGENT_PATH)]),
-            ..Default::default()
-        };
-        let exec = doc.create_exec(container_name, config).await?;
-        if let StartExecResults::Attached { mut input, .. } = doc.start_exec(&exec.id, None).await? {
-            input.write_all(AGENT).await.unwrap();
-            let chmod_config = CreateExecOptions::<String> {
-                attach_stdin: Some(false),
-                cmd: Some(vec!["chmod".to_string(), "+x".to_string(), AGENT_PATH.to_string()]),
-                ..Default::default()
+        let arch = run_exec(doc, container_name, vec!["uname".to_string(), "-m".to_string()], None).await?;
+        let agent = get_agent(&arch).ok_or(Error::UnsupportedArchitecture(unsupported_architecture_message(&arch)))?;
+        let target = agent_target(container_name);
+        if session.prefers_memfd() {
+            if memfd_supported(doc, container_name).await {
+                session.set_location(target, AgentLocation::Memfd(agent));
+                return Ok(());
+            }
+            eprintln!("Warning: no python3 with memfd_create in {}, installing the agent into a directory", container_name);
+        }
+        let home = run_exec(doc, container_name, vec!["printenv".to_string(), "HOME".to_string()], None).await.ok();
+        for dir in session.get_agent_dirs(vec![], home) {
+            let exec = |cmd: Vec<String>, input: Option<&'static [u8]>| {
+                let (doc, container) = (doc.clone(), container_name.to_owned());
+                async move { run_exec(&doc, &container, cmd, input).await.map_err(|e| format!("{:?}", e)) }
             };
-            let chmod_exec = doc.create_exec(container_name, chmod_config).await?;
-            doc.start_exec(&chmod_exec.id, None).await?;
+            match install_agent_into(exec, &dir, agent, session).await {
+                Ok(path) => {
+                    session.set_location(target, AgentLocation::Path(path.clone()));
+                    let (doc, container) = (doc.clone(), container_name.to_owned());
+                    session.on_shutdown(format!("agent copy {} in container {}", path, container_name), async move {
+                        run_exec(&doc, &container, vec!["rm".to_string(), "-f".to_string(), path], None).await.map(|_| ()).map_err(|e| format!("{:?}", e))
+                    });
+                    return Ok(());
+                },
+                Err(e) => log::info!("Agent install into {} of {} failed: {:?}", dir, container_name, e),
+            }
+        }
+        // no writable and executable directory, run from memory
+        if !session.prefers_memfd() && memfd_supported(doc, container_name).await {
+            session.set_location(target, AgentLocation::Memfd(agent));
             return Ok(());
-        } else {
-            return Err(Error::DockerError("Failed to exec dd in docker container".to_string()));
         }
+        Err(Error::DockerError(format!("No directory to install agent into in {}", container_name)))
     }
 
-    
-    
+    // Starts container sharing network namespace of the target container
+    pub async fn start_sidecar(&self, container_name: &str, image: &str) -> Result<Sidecar, Error> {
+        let doc = match &self.docker {
+            Ok(d) => d,
+            Err(e) => return Err(e.clone())
+        };
+        if doc.inspect_image(image).await.is_err() {
+            let options = CreateImageOptions { from_image: image, ..Default::default() };
+            let mut pull = doc.create_image(Some(options), None, None);
+            while let Some(info) = pull.next().await {
+                info?;
+            }
+        }
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let name = format!("rs-agent-{}-{:x}", container_name, started);
+        let config = Config {
+            image: Some(image.to_string()),
+            cmd: Some(vec!["sleep".to_string(), SIDECAR_LIFETIME.to_string()]),
+            labels: Some(HashMap::from([("app.kubernetes.io/managed-by".to_string(), "rs".to_string())])),
+            host_config: Some(HostConfig {
+                network_mode: Some(format!("container:{}", container_name)),
+                auto_remove: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        doc.create_container(Some(CreateContainerOptions { name: name.clone() }), config).await?;
+        let sidecar = Sidecar { docker: doc.clone(), name };
+        if let Err(e) = doc.start_container::<String>(&sidecar.name, None).await {
+            sidecar.remove().await.ok();
+            return Err(e.into());
+        }
+        log::info!("Started sidecar container: {}", sidecar.name);
+        Ok(sidecar)
+    }
 
     pub async fn get_copy_source(&self, container: &str, path: &str) -> Result<PipeCopySource, Error> {
         let path_parts = Path::new(path);
@@ -270,7 +331,30 @@ impl DockerEndpoint {
 }
 
 impl DockerEndpoint {
-    pub async fn connect(&self, container_name: &str, agent: Vec<String>) -> Result<impl AsyncRead + AsyncWrite, Error> {
+    pub async fn connect(&self, container_name: &str, session: &AgentSession, args: &[&str]) -> Result<impl AsyncRead + AsyncWrite, Error> {
+        let target = agent_target(container_name);
+        // agent run from memory is uploaded once and connects to every destination
+        if session.runs_from_memory(&target) {
+            let destination = dynamic_destination(args).ok_or(Error::DockerError(format!("Agent could not connect to {}", args.join(" "))))?;
+            let connector = session.get_connector(&target, self.open_connections(container_name, session, &["--dynamic"])).await?;
+            return socks::dial(&connector, &destination).await
+                .map_err(|status| Error::DockerError(format!("Failed to connect to {}: reply {}", destination, status)));
+        }
+        let agent = installed_agent_command(session, container_name, args)?;
+        let out = self.attach(container_name, agent.cmd, agent.preload).await?;
+        let (mut out_reader, mut out_writer) = split(out);
+        handshake(&mut out_reader, &mut out_writer, 0).await?;
+        Ok(out_reader.unsplit(out_writer))
+    }
+
+    // Process started with `sh -c` in the container
+    pub async fn exec(&self, container_name: &str, command: &str) -> Result<DuplexStream, Error> {
+        let cmd = vec!["sh".to_string(), "-c".to_string(), command.to_string()];
+        self.attach(container_name, cmd, None).await
+    }
+
+    // Runs the command with stdin preloaded, its stderr goes to local stderr
+    async fn attach(&self, container_name: &str, cmd: Vec<String>, preload: Option<&[u8]>) -> Result<DuplexStream, Error> {
         let doc = match &self.get_docker() {
             Ok(d) => d,
             Err(e) => return Err(e.clone())
@@ -278,12 +362,16 @@ impl DockerEndpoint {
         let config = CreateExecOptions::<String> {
             attach_stdout: Some(true),
             attach_stdin: Some(true),
-            cmd: Some(agent),
+            attach_stderr: Some(true),
+            cmd: Some(cmd),
             ..Default::default()
         };
         let exec = doc.create_exec(container_name, config).await?;
         match doc.start_exec(&exec.id, None).await? {
             StartExecResults::Attached { mut output, mut input} => {
+                if let Some(preload) = preload {
+                    input.write_all(preload).await.or(Err(Error::DockerError("Failed to upload agent".to_string())))?;
+                }
                 // split output stream into stdout
                 let (stdio, out) = duplex(255);
                 let (mut reader, mut writer) = split(stdio);
@@ -333,23 +421,40 @@ impl DockerEndpoint {
         
     }
 
-    pub async fn get_connections(&self, container_name: &str, agent: Vec<String>) -> Result<Receiver<impl AsyncRead + AsyncWrite>, Error> {
+    // Connections accepted by the agent, tagged with the port they were accepted on
+    pub async fn get_connections(&self, container_name: &str, session: &AgentSession, args: &[&str]) -> Result<Receiver<(DuplexStream, String)>, Error> {
+        let (out, input, mux) = self.start_agent(container_name, session, args, FEATURE_PORT_TAGS).await?;
+        Ok(mux.accept_destinations(out, input))
+    }
+
+    // Connections to destinations the agent connects to
+    pub async fn open_connections(&self, container_name: &str, session: &AgentSession, args: &[&str]) -> Result<MuxConnector, Error> {
+        let (out, input, mux) = self.start_agent(container_name, session, args, FEATURE_DYNAMIC_DESTINATIONS).await?;
+        Ok(mux.open_connections(out, input))
+    }
+
+    // Runs agent multiplexing connections over its stdio, it is stopped on session shutdown
+    async fn start_agent(&self, container_name: &str, session: &AgentSession, args: &[&str], features: u8) -> Result<(DuplexStream, Pin<Box<dyn AsyncWrite + Send>>, Multiplexer), Error> {
         let doc = match &self.get_docker() {
             Ok(d) => d,
             Err(e) => return Err(e.clone())
         }.to_owned();
+        let agent = installed_agent_command(session, container_name, args)?;
 
         let config = CreateExecOptions::<String> {
             attach_stdout: Some(true),
             attach_stdin: Some(true),
-            cmd: Some(agent),
+            cmd: Some(agent.cmd),
             ..Default::default()
         };
         let exec = doc.create_exec(container_name, config).await?;
         match doc.start_exec(&exec.id, None).await? {
-            StartExecResults::Attached { mut output, input} => {
+            StartExecResults::Attached { mut output, mut input} => {
+                if let Some(preload) = agent.preload {
+                    input.write_all(preload).await.or(Err(Error::DockerError("Failed to upload agent".to_string())))?;
+                }
                 // split output stream into stdout
-                let (mut stdout, out) = duplex(255);
+                let (mut stdout, mut out) = duplex(255);
                 tokio::spawn(async move {
                         let mut stderr = stderr();
                         while let Some(Ok(msg)) = output.next().await {
@@ -370,20 +475,23 @@ impl DockerEndpoint {
                             };
                     }
                 });
-                // kill switch
-                let cn = container_name.to_owned();
-                tokio::spawn(async move {
-                    tokio::signal::ctrl_c().await.unwrap();
-                    let kill_config = CreateExecOptions::<String> {
-                        attach_stdin: Some(false),
-                        cmd: Some(vec!["touch".to_string(), AGENT_KILL_PATH.to_string()]),
-                        ..Default::default()
-                    };
-                    let kill_exec = doc.create_exec(&cn, kill_config).await.unwrap();
-                    doc.start_exec(&kill_exec.id, None).await.unwrap(); 
+                let mux = Multiplexer::new();
+                let agent_shutdown = mux.shutdown_handle();
+                // stop remote agent and wait for it to exit
+                let exec_id = exec.id.clone();
+                session.on_shutdown(format!("agent in container {}", container_name), async move {
+                    agent_shutdown.shutdown().await;
+                    for _ in 0..AGENT_SHUTDOWN_ATTEMPTS {
+                        match doc.inspect_exec(&exec_id).await {
+                            Ok(inspect) if inspect.running == Some(true) => sleep(Duration::from_millis(100)).await,
+                            _ => return Ok(()),
+                        }
+                    }
+                    Err("agent did not exit".to_string())
                 });
 
-                Ok(Multiplexer::new().produce_connections(out, input))
+                handshake(&mut out, &mut input, features).await?;
+                Ok((out, input, mux))
             },
             _ => {
                 Err(Error::FailedToInitDocker)
@@ -393,4 +501,73 @@ impl DockerEndpoint {
     }
 
 
-}
\ No newline at end of file
+}
+
+// Key of the agent installed into container
+fn agent_target(container_name: &str) -> String {
+    format!("docker/{}", container_name)
+}
+
+fn installed_agent_command(session: &AgentSession, container_name: &str, args: &[&str]) -> Result<AgentCommand, Error> {
+    session.get_agent_command(&agent_target(container_name), args)
+        .ok_or(Error::DockerError(format!("Agent is not installed in {}", container_name)))
+}
+
+async fn memfd_supported(doc: &Docker, container_name: &str) -> bool {
+    run_exec(doc, container_name, MEMFD_CHECK.iter().map(|a| a.to_string()).collect(), None).await.is_ok()
+}
+
+// Runs command in the container and returns its stdout, fails on non zero exit code
+async fn run_exec(doc: &Docker, container_name: &str, cmd: Vec<String>, input_data: Option<&[u8]>) -> Result<String, Error> {
+    let config = CreateExecOptions::<String> {
+        attach_stdin: Some(input_data.is_some()),
+        attach_stdout: Some(true),
+        attach_stderr: Some(true),
+        cmd: Some(cmd.clone()),
+        ..Default::default()
+    };
+    let exec = doc.create_exec(container_name, config).await?;
+    let mut stdout = String::new();
+    if let StartExecResults::Attached { mut output, mut input } = doc.start_exec(&exec.id, None).await? {
+        if let Some(data) = input_data {
+            // failed write shows up in the exit code
+            if input.write_all(data).await.is_ok() {
+                input.shutdown().await.ok();
+            }
+        }
+        while let Some(Ok(msg)) = output.next().await {
+            if let LogOutput::StdOut { message } = msg {
+                stdout.push_str(&String::from_utf8_lossy(&message));
+            }
+        }
+    }
+    for _ in 0..100 {
+        let inspect = doc.inspect_exec(&exec.id).await?;
+        match (inspect.running, inspect.exit_code) {
+            (Some(true), _) => sleep(Duration::from_millis(50)).await,
+            (_, Some(0)) => return Ok(stdout),
+            (_, code) => return Err(Error::DockerError(format!("{} failed with exit code {:?}", cmd[0], code))),
+        }
+    }
+    Err(Error::DockerError(format!("{} did not finish", cmd[0])))
+}
+
+// Container created to run the agent for containers without a shell
+#[derive(Clone)]
+pub struct Sidecar {
+    docker: Docker,
+    name: String
+}
+
+impl Sidecar {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn remove(&self) -> Result<(), Error> {
+        let options = RemoveContainerOptions { force: true, ..Default::default() };
+        self.docker.remove_container(&self.name, Some(options)).await?;
+        log::info!("Removed sidecar container: {}", self.name);
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+// HTTP proxy server side, CONNECT tunnels and plain http:// absolute-URI requests
+static MAX_HEAD_SIZE: usize = 16 * 1024;
+
+// Hop-by-hop headers meant for the proxy, requests are forwarded with `Connection: close`
+static PROXY_HEADERS: [&str; 4] = ["connection", "proxy-connection", "keep-alive", "proxy-authorization"];
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    IoError(String),
+    HeadTooLarge,
+    BadRequest(String)
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IoError(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    // host:port to connect to
+    pub destination: String,
+    // CONNECT tunnel, otherwise head has to be sent to the destination first
+    pub tunnel: bool,
+    pub head: Vec<u8>
+}
+
+// Reads request head from the client, absolute-URI requests are rewritten to origin form
+pub async fn accept(con: &mut (impl AsyncBufRead + Unpin)) -> Result<Request, Error> {
+    let mut lines = vec![];
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        if con.read_line(&mut line).await? == 0 {
+            return Err(Error::BadRequest("incomplete request head".to_string()));
+        }
+        size += line.len();
+        if size > MAX_HEAD_SIZE {
+            return Err(Error::HeadTooLarge);
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]).to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    let request_line: Vec<&str> = lines.first().map(|l| l.split(' ').collect()).unwrap_or_default();
+    let (method, target, version) = match request_line.as_slice() {
+        [method, target, version] => (*method, *target, *version),
+        _ => return Err(Error::BadRequest("malformed request line".to_string())),
+    };
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let destination = match target.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => target.to_string(),
+            _ => format!("{}:443", target),
+        };
+        return Ok(Request { destination, tunnel: true, head: vec![] });
+    }
+
+    let uri = target.strip_prefix("http://").ok_or(Error::BadRequest(format!("{} is not an absolute http:// URI", target)))?;
+    let (authority, path) = match uri.find('/') {
+        Some(i) => (&uri[..i], &uri[i..]),
+        None => (uri, "/"),
+    };
+    if authority.is_empty() {
+        return Err(Error::BadRequest(format!("{} has no host", target)));
+    }
+    let destination = match authority.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => authority.to_string(),
+        _ => format!("{}:80", authority),
+    };
+    let mut head = format!("{} {} {}\r\n", method, path, version);
+    for header in &lines[1..] {
+        let name = header.split(':').next().unwrap_or_default().trim().to_ascii_lowercase();
+        if !PROXY_HEADERS.contains(&name.as_str()) {
+            head.push_str(header);
+            head.push_str("\r\n");
+        }
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    Ok(Request { destination, tunnel: false, head: head.into_bytes() })
+}
+
+pub async fn respond(con: &mut (impl AsyncWrite + Unpin), status: u16, reason: &str) -> Result<(), Error> {
+    let response = match status {
+        200 => format!("HTTP/1.1 {} {}\r\n\r\n", status, reason),
+        _ => format!("HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status, reason),
+    };
+    con.write_all(response.as_bytes()).await?;
+    con.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn accepts_connect_and_absolute_uri() {
+        let mut con: &[u8] = b"CONNECT orders.payments.svc:8443 HTTP/1.1\r\nHost: orders.payments.svc:8443\r\n\r\n";
+        let req = accept(&mut con).await.unwrap();
+        assert_eq!(req, Request { destination: "orders.payments.svc:8443".to_string(), tunnel: true, head: vec![] });
+
+        let mut con: &[u8] = b"GET http://orders.payments.svc/api?id=1 HTTP/1.1\r\nHost: orders.payments.svc\r\nProxy-Connection: keep-alive\r\n\r\nbody";
+        let req = accept(&mut con).await.unwrap();
+        assert_eq!(req.destination, "orders.payments.svc:80");
+        assert!(!req.tunnel);
+        assert_eq!(String::from_utf8(req.head).unwrap(), "GET /api?id=1 HTTP/1.1\r\nHost: orders.payments.svc\r\nConnection: close\r\n\r\n");
+        assert_eq!(con, b"body");
+
+        let mut con: &[u8] = b"GET /api HTTP/1.1\r\n\r\n";
+        assert!(matches!(accept(&mut con).await, Err(Error::BadRequest(_))));
+    }
+}
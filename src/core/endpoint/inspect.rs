@@ -0,0 +1,345 @@
+use std::{collections::VecDeque, sync::atomic::{AtomicU64, Ordering}, time::{Instant, SystemTime, UNIX_EPOCH}};
+
+use super::{ConnectionAddresses, ConnectionTap, Direction, Tap, TapWriter};
+
+static HEX_LINE_SIZE: usize = 16;
+// Heads longer than this are not HTTP/1.x
+static MAX_HEAD_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum View {
+    Hex,
+    Text,
+    // request and response lines of HTTP/1.x messages
+    Http
+}
+
+// Prints traffic of forwarded connections on stderr, connections are numbered from 1
+pub struct Inspector {
+    view: View,
+    connections: AtomicU64,
+    writer: TapWriter
+}
+
+impl Inspector {
+    pub fn new(view: View) -> Self {
+        Inspector { view, connections: AtomicU64::new(0), writer: TapWriter::spawn(tokio::io::stderr()) }
+    }
+}
+
+impl Tap for Inspector {
+    fn open(&self, addresses: Option<ConnectionAddresses>) -> Box<dyn ConnectionTap> {
+        let id = self.connections.fetch_add(1, Ordering::Relaxed) + 1;
+        let from = addresses.map(|a| format!(" from {}", a.peer)).unwrap_or_default();
+        self.writer.write(format!("{} #{} opened{}\n", timestamp(), id, from).into_bytes());
+        Box::new(ConnectionInspector::new(id, self.view, self.writer.clone()))
+    }
+
+    fn writer(&self) -> &TapWriter {
+        &self.writer
+    }
+}
+
+struct ConnectionInspector {
+    id: u64,
+    view: View,
+    // bytes seen outbound and inbound
+    sizes: [u64; 2],
+    http: [HttpStream; 2],
+    // methods of requests waiting for responses and when they were sent
+    requests: VecDeque<(String, Instant)>,
+    writer: TapWriter
+}
+
+impl ConnectionInspector {
+    fn new(id: u64, view: View, writer: TapWriter) -> Self {
+        ConnectionInspector { id, view, sizes: [0, 0], http: [HttpStream::new(), HttpStream::new()], requests: VecDeque::new(), writer }
+    }
+
+    // Entries describing the bytes, hexdump and text views print all of them below the size
+    fn lines(&mut self, direction: Direction, bytes: &[u8]) -> Vec<String> {
+        let side = direction as usize;
+        let offset = self.sizes[side];
+        self.sizes[side] += bytes.len() as u64;
+        match self.view {
+            View::Hex => vec![format!("{} bytes\n{}", bytes.len(), hexdump(offset, bytes))],
+            View::Text => vec![format!("{} bytes\n{}", bytes.len(), text(bytes))],
+            View::Http => self.http_lines(direction, bytes),
+        }
+    }
+
+    fn http_lines(&mut self, direction: Direction, mut bytes: &[u8]) -> Vec<String> {
+        let side = direction as usize;
+        let mut lines = vec![];
+        while !bytes.is_empty() {
+            let head = match self.http[side].next(&mut bytes) {
+                Some(Ok(head)) => head,
+                Some(Err(e)) => {
+                    lines.push(format!("not HTTP/1.x ({}), ignoring the rest", e));
+                    break;
+                },
+                None => break,
+            };
+            let body = head.body();
+            let (summary, state) = match direction {
+                Direction::Outbound => {
+                    let method = head.start_line.split(' ').next().unwrap_or_default();
+                    self.requests.push_back((method.to_string(), Instant::now()));
+                    let host = head.header("host").map(|h| format!(" (host {})", h)).unwrap_or_default();
+                    let state = match method {
+                        // the client speaks the tunneled protocol from now on
+                        "CONNECT" => State::Opaque,
+                        _ => body,
+                    };
+                    (format!("{}{}", head.start_line, host), state)
+                },
+                Direction::Inbound => {
+                    let status = head.start_line.split(' ').nth(1).and_then(|s| s.parse::<u16>().ok()).unwrap_or_default();
+                    // interim responses do not answer the request
+                    let request = match status {
+                        100..=199 if status != 101 => None,
+                        _ => self.requests.pop_front(),
+                    };
+                    let method = request.as_ref().map(|(m, _)| m.as_str()).unwrap_or_default();
+                    let state = match (method, status) {
+                        ("CONNECT", 200..=299) | (_, 101) => State::Opaque,
+                        ("HEAD", _) | (_, 100..=199) | (_, 204) | (_, 304) => State::Head,
+                        // body is read until the connection closes
+                        (_, _) if matches!(body, State::Head) && head.header("content-length").is_none() => State::Opaque,
+                        _ => body,
+                    };
+                    let mut summary = head.start_line.clone();
+                    match state {
+                        State::Chunks => summary.push_str(", chunked"),
+                        State::Body(size) => summary.push_str(&format!(", {} bytes", size)),
+                        _ => {},
+                    }
+                    if let Some((_, sent)) = request {
+                        summary.push_str(&format!(", {} ms", sent.elapsed().as_millis()));
+                    }
+                    (summary, state)
+                },
+            };
+            lines.push(summary);
+            self.http[side].state = state;
+        }
+        lines
+    }
+}
+
+impl ConnectionTap for ConnectionInspector {
+    fn data(&mut self, direction: Direction, bytes: &[u8]) {
+        let arrow = match direction {
+            Direction::Outbound => ">",
+            Direction::Inbound => "<",
+        };
+        let lines = self.lines(direction, bytes);
+        if lines.is_empty() {
+            return;
+        }
+        // a single write keeps lines of concurrent connections apart
+        let ts = timestamp();
+        let out: String = lines.iter().map(|l| format!("{} #{} {} {}\n", ts, self.id, arrow, l.trim_end())).collect();
+        self.writer.write(out.into_bytes());
+    }
+
+    fn close(&mut self) {
+        self.writer.write(format!("{} #{} closed, {} bytes sent, {} bytes received\n", timestamp(), self.id, self.sizes[0], self.sizes[1]).into_bytes());
+    }
+}
+
+// UTC time of day with milliseconds
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs() % 86400;
+    format!("{:02}:{:02}:{:02}.{:03}", secs / 3600, secs / 60 % 60, secs % 60, now.subsec_millis())
+}
+
+// Same format as `hexdump -C`, offsets count from the start of the direction
+pub fn hexdump(offset: u64, bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(HEX_LINE_SIZE).enumerate() {
+        let mut hex = String::new();
+        for (j, b) in line.iter().enumerate() {
+            if j == HEX_LINE_SIZE / 2 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{:02x} ", b));
+        }
+        let printable: String = line.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }).collect();
+        out.push_str(&format!("{:08x}  {:<49} |{}|\n", offset + (i * HEX_LINE_SIZE) as u64, hex, printable));
+    }
+    out
+}
+
+// Printable ASCII and line breaks, anything else is replaced with dots
+pub fn text(bytes: &[u8]) -> String {
+    bytes.iter().filter(|b| **b != b'\r').map(|b| match b {
+        b'\n' | b'\t' => *b as char,
+        b if b.is_ascii_graphic() || *b == b' ' => *b as char,
+        _ => '.',
+    }).collect()
+}
+
+// What the next bytes of a direction are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Head,
+    // bytes of the body left
+    Body(u64),
+    // chunk size line
+    Chunks,
+    // bytes of the chunk and its line break left
+    Chunk(u64),
+    // trailer fields after the last chunk
+    Trailer,
+    // not HTTP/1.x, tunnels and bodies ended by closing the connection
+    Opaque
+}
+
+struct HttpHead {
+    start_line: String,
+    headers: Vec<(String, String)>
+}
+
+impl HttpHead {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    // Body of requests and responses with a known length
+    fn body(&self) -> State {
+        let chunked = self.header("transfer-encoding").map(|e| e.to_ascii_lowercase().contains("chunked")).unwrap_or_default();
+        match (chunked, self.header("content-length").and_then(|l| l.parse::<u64>().ok())) {
+            (true, _) => State::Chunks,
+            (false, Some(size)) if size > 0 => State::Body(size),
+            _ => State::Head,
+        }
+    }
+}
+
+// Finds message heads in one direction of a connection, skipping bodies
+struct HttpStream {
+    state: State,
+    buf: Vec<u8>
+}
+
+impl HttpStream {
+    fn new() -> Self {
+        HttpStream { state: State::Head, buf: vec![] }
+    }
+
+    // Consumes bytes up to the end of the next head, the caller sets state of the body
+    fn next(&mut self, bytes: &mut &[u8]) -> Option<Result<HttpHead, String>> {
+        while !bytes.is_empty() {
+            match self.state {
+                State::Opaque => {
+                    *bytes = &[];
+                    return None;
+                },
+                State::Body(left) | State::Chunk(left) => {
+                    let size = left.min(bytes.len() as u64);
+                    *bytes = &bytes[size as usize..];
+                    self.state = match (self.state, left - size) {
+                        (State::Body(_), 0) => State::Head,
+                        (State::Body(_), left) => State::Body(left),
+                        (_, 0) => State::Chunks,
+                        (_, left) => State::Chunk(left),
+                    };
+                },
+                State::Head | State::Chunks | State::Trailer => {
+                    let end: &[u8] = if self.state == State::Head { b"\r\n\r\n" } else { b"\r\n" };
+                    let mut used = 0;
+                    let mut found = false;
+                    for b in bytes.iter() {
+                        self.buf.push(*b);
+                        used += 1;
+                        if self.buf.ends_with(end) {
+                            found = true;
+                            break;
+                        }
+                    }
+                    *bytes = &bytes[used..];
+                    // start lines are text, binary protocols are recognized before their heads end
+                    let start_line = self.buf.split(|b| *b == b'\r').next().unwrap_or_default();
+                    if self.state == State::Head && start_line.iter().any(|b| !b.is_ascii_graphic() && *b != b' ' && *b != b'\n') {
+                        self.state = State::Opaque;
+                        return Some(Err("binary data".to_string()));
+                    }
+                    if !found {
+                        if self.buf.len() > MAX_HEAD_SIZE {
+                            self.state = State::Opaque;
+                            return Some(Err("head too large".to_string()));
+                        }
+                        return None;
+                    }
+                    let line = String::from_utf8_lossy(&std::mem::take(&mut self.buf)).to_string();
+                    match self.state {
+                        // line breaks left after bodies
+                        State::Head if line.trim().is_empty() => {},
+                        State::Head => return Some(self.head(&line)),
+                        State::Chunks => {
+                            let size = line.split(';').next().map(|s| u64::from_str_radix(s.trim(), 16));
+                            self.state = match size {
+                                Some(Ok(0)) => State::Trailer,
+                                Some(Ok(size)) => State::Chunk(size + 2),
+                                _ => {
+                                    self.state = State::Opaque;
+                                    return Some(Err("malformed chunk".to_string()));
+                                },
+                            };
+                        },
+                        _ => if line == "\r\n" {
+                            self.state = State::Head;
+                        },
+                    }
+                },
+            }
+        }
+        None
+    }
+
+    fn head(&mut self, head: &str) -> Result<HttpHead, String> {
+        let mut lines = head.trim_start().split("\r\n");
+        let start_line = lines.next().unwrap_or_default().to_string();
+        let parts: Vec<&str> = start_line.splitn(3, ' ').collect();
+        let valid = match parts.as_slice() {
+            [version, status, _] | [version, status] if version.starts_with("HTTP/1.") => status.parse::<u16>().is_ok(),
+            [method, _, version] => version.starts_with("HTTP/1.") && method.chars().all(|c| c.is_ascii_uppercase()),
+            _ => false,
+        };
+        if !valid {
+            self.state = State::Opaque;
+            return Err(format!("unexpected start line {:?}", start_line.chars().take(64).collect::<String>()));
+        }
+        let headers = lines.filter_map(|l| l.split_once(':')).map(|(n, v)| (n.trim().to_string(), v.trim().to_string())).collect();
+        Ok(HttpHead { start_line, headers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn views_describe_traffic() {
+        assert_eq!(
+            hexdump(16, b"GET / HTTP/1.1\r\n\x00"),
+            "00000010  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|\n\
+             00000020  00                                                |.|\n"
+        );
+        assert_eq!(text(b"a\r\nb\x01\tc"), "a\nb.\tc");
+
+        let mut con = ConnectionInspector::new(1, View::Http, TapWriter::spawn(tokio::io::sink()));
+        assert_eq!(con.lines(Direction::Outbound, b"POST /orders HTTP/1.1\r\nHost: orders\r\nContent-Length: 4\r\n\r\nbodyGET /"), vec!["POST /orders HTTP/1.1 (host orders)"]);
+        assert_eq!(con.lines(Direction::Outbound, b"orders/1 HTTP/1.1\r\n\r\n"), vec!["GET /orders/1 HTTP/1.1"]);
+        let lines = con.lines(Direction::Inbound, b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}");
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("HTTP/1.1 201 Created, chunked, "));
+        assert!(lines[1].starts_with("HTTP/1.1 200 OK, 2 bytes, "));
+
+        let mut con = ConnectionInspector::new(2, View::Http, TapWriter::spawn(tokio::io::sink()));
+        assert_eq!(con.lines(Direction::Outbound, b"\x16\x03\x01\x02\x00\x01"), vec!["not HTTP/1.x (binary data), ignoring the rest"]);
+        assert!(con.lines(Direction::Outbound, b"GET / HTTP/1.1\r\n\r\n").is_empty());
+    }
+}
@@ -1,11 +1,24 @@
-use std::{fs::read_dir, collections::HashMap, rc::Rc, process::exit, str::FromStr, path::Path};
+use std::{fs::read_dir, collections::{HashMap, BTreeMap}, rc::Rc, str::FromStr, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
 use home::home_dir;
-use k8s_openapi::api::core::v1::{Namespace, Pod};
-use kube::{config::{Kubeconfig, KubeConfigOptions, KubeconfigError}, Client, Config, api::{Portforwarder, ListParams, AttachParams}, Api, ResourceExt};
-use tokio::{io::{AsyncRead, AsyncWrite, split, copy, BufReader, AsyncBufReadExt, stderr, AsyncWriteExt, AsyncReadExt}, sync::mpsc::Receiver, select, time::sleep};
-
-use super::{PipeEndpoint, AGENT, AGENT_PATH, AGENT_KILL_PATH, PipeCopySource, PipeCopyDestination};
-use crate::mux::Multiplexer;
+use k8s_openapi::{api::core::v1::{Namespace, Pod, PodSpec, Container, ContainerStatus}, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+use kube::{config::{Kubeconfig, KubeConfigOptions, KubeconfigError}, Client, Config, api::{Portforwarder, ListParams, AttachParams, AttachedProcess, PostParams, DeleteParams, PatchParams, Patch}, Api, ResourceExt};
+use tokio::{io::{AsyncRead, AsyncWrite, split, copy, BufReader, AsyncBufReadExt, stderr, AsyncWriteExt, AsyncReadExt}, sync::mpsc::Receiver, select, io::DuplexStream, time::{Instant, sleep, timeout}};
+
+use super::{PipeEndpoint, AgentSession, AgentLocation, AgentCommand, MEMFD_CHECK, PipeCopySource, PipeCopyDestination, get_agent, unsupported_architecture_message, install_agent_into, dynamic_destination, socks};
+use crate::{mux::{Multiplexer, MuxConnector, MuxShutdown}, handshake::{self, handshake, FEATURE_DYNAMIC_DESTINATIONS, FEATURE_PORT_TAGS}};
+
+// Relay pod and ephemeral container exit on their own after this many seconds
+static RELAY_LIFETIME: u64 = 24 * 60 * 60;
+// Relay pod and ephemeral container start is polled this often
+static START_POLL_INTERVAL: Duration = Duration::from_millis(500);
+static EPHEMERAL_START_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// Waiting reasons of containers that do not start without someone fixing the pod or the image
+static FATAL_WAITING_REASONS: [&str; 6] = ["ErrImagePull", "ImagePullBackOff", "ErrImageNeverPull", "InvalidImageName", "CreateContainerConfigError", "CreateContainerError"];
+static EPHEMERAL_CONTAINER_PREFIX: &str = "rs-agent";
+// Older servers expect an EphemeralContainers object instead of the pod, behind a feature gate
+static EPHEMERAL_CONTAINERS_MIN_VERSION: (u32, u32) = (1, 23);
+// seconds to wait for the agent to exit after shutdown message
+static AGENT_SHUTDOWN_TIMEOUT: u64 = 5;
 
 // Files holding Kube config
 struct KubeConfigInFile {
@@ -18,7 +31,17 @@ pub enum Error {
     KubeError(kube::Error),
     KubeConfigError(KubeconfigError),
     ContextNotFound(String),
-    ExecError(String)
+    ExecError(String),
+    RelayError(String),
+    EphemeralContainerError(String),
+    UnsupportedArchitecture(String),
+    HandshakeError(handshake::Error)
+}
+
+impl From<handshake::Error> for Error {
+    fn from(e: handshake::Error) -> Self {
+        Self::HandshakeError(e)
+    }
 }
 
 impl From<kube::Error> for Error {
@@ -123,27 +146,47 @@ impl KubeConfigs {
         return Ok(lines_vec);
     }
 
-    pub async fn install_agent(&self, context: String, ns: String, pod: String) -> Result<(), Error> {
-        let client = self.get_client(context).await?;
+    pub async fn install_agent(&self, context: String, ns: String, pod: String, container: Option<String>, session: &AgentSession) -> Result<(), Error> {
+        let client = self.get_client(context.clone()).await?;
         let pods = Api::<Pod>::namespaced(client, ns.as_str());
-        // upload file
-        let mut params = AttachParams::default();
-        params.stdin = true;
-        let mut upload_proc = pods
-            .exec(pod.as_str(), ["dd", format!("of={}", AGENT_PATH).as_str()], &params)
-            .await?;
-        let mut stdin = upload_proc.stdin().ok_or(Error::ExecError("Failed to exec dd".to_string()))?;
-        stdin.write_all(AGENT).await.or(Err(Error::ExecError("Failed to exec tee".to_string())))?;
-        stdin.shutdown().await.expect("Shutdown of agent copy failed");
-        drop(stdin);
-        // chmod
-        params.stdin = false;
-        params.stdout = false;
-        let chmod_proc = pods
-            .exec(pod.as_str(), ["chmod", "+x", AGENT_PATH], &params)
-            .await?;
-        chmod_proc.join().await.or(Err(Error::ExecError("Failed to exec chmod".to_string())))?;
-        return Ok(());
+        let arch = exec_output(&pods, &pod, container.clone(), &["uname", "-m"]).await?;
+        let agent = get_agent(&arch).ok_or(Error::UnsupportedArchitecture(unsupported_architecture_message(&arch)))?;
+        let target = agent_target(&context, &ns, &pod, &container);
+        if session.prefers_memfd() {
+            if memfd_supported(&pods, &pod, container.clone()).await {
+                session.set_location(target, AgentLocation::Memfd(agent));
+                return Ok(());
+            }
+            eprintln!("Warning: no python3 with memfd_create in {}, installing the agent into a directory", pod);
+        }
+        let mounts = empty_dir_mounts(&pods, &pod, &container).await;
+        let home = exec_output(&pods, &pod, container.clone(), &["printenv", "HOME"]).await.ok();
+        for dir in session.get_agent_dirs(mounts, home) {
+            let exec = |cmd: Vec<String>, input: Option<&'static [u8]>| {
+                let (pods, pod, container) = (pods.clone(), pod.clone(), container.clone());
+                async move {
+                    let cmd: Vec<&str> = cmd.iter().map(String::as_str).collect();
+                    exec_input(&pods, &pod, container, &cmd, input).await.map_err(|e| format!("{:?}", e))
+                }
+            };
+            match install_agent_into(exec, &dir, agent, session).await {
+                Ok(path) => {
+                    session.set_location(target, AgentLocation::Path(path.clone()));
+                    let (pods, description) = (pods.clone(), format!("agent copy {} in pod {}", path, pod));
+                    session.on_shutdown(description, async move {
+                        exec_output(&pods, &pod, container, &["rm", "-f", &path]).await.map(|_| ()).map_err(|e| format!("{:?}", e))
+                    });
+                    return Ok(());
+                },
+                Err(e) => log::info!("Agent install into {} of {} failed: {:?}", dir, pod, e),
+            }
+        }
+        // no writable and executable directory, run from memory
+        if !session.prefers_memfd() && memfd_supported(&pods, &pod, container).await {
+            session.set_location(target, AgentLocation::Memfd(agent));
+            return Ok(());
+        }
+        Err(Error::ExecError(format!("No directory to install agent into in {}", pod)))
     }
 
 
@@ -275,36 +318,329 @@ impl KubeConfigs {
         Ok(PipeCopyDestination::new(Box::new(stdin_stream)))
     }
 
+    pub async fn create_relay(&self, context: String, ns: String, image: String, node_selector: Vec<(String, String)>, start_timeout: Duration) -> Result<RelayPod, Error> {
+        let client = self.get_client(context).await?;
+        let pods = Api::<Pod>::namespaced(client, &ns);
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let name = format!("rs-relay-{}-{:x}", std::process::id(), started);
+        let pod = Pod {
+            metadata: ObjectMeta {
+                name: Some(name.clone()),
+                labels: Some(BTreeMap::from([("app.kubernetes.io/managed-by".to_owned(), "rs".to_owned())])),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    name: "relay".to_owned(),
+                    image: Some(image),
+                    command: Some(vec!["sleep".to_owned(), RELAY_LIFETIME.to_string()]),
+                    ..Default::default()
+                }],
+                node_selector: match node_selector.is_empty() {
+                    true => None,
+                    false => Some(node_selector.into_iter().collect()),
+                },
+                restart_policy: Some("Never".to_owned()),
+                termination_grace_period_seconds: Some(0),
+                automount_service_account_token: Some(false),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        pods.create(&PostParams::default(), &pod).await?;
+        log::info!("Created relay pod: {}/{}", ns, name);
+        let relay = RelayPod { pods, name };
+
+        // wait for the relay to be able to exec the agent
+        let deadline = Instant::now() + start_timeout;
+        let reason = loop {
+            let status = relay.pods.get(&relay.name).await?.status.unwrap_or_default();
+            match status.phase.as_deref() {
+                Some("Running") => return Ok(relay),
+                Some(phase @ ("Failed" | "Succeeded")) => break format!("exited ({})", phase),
+                _ => {},
+            }
+            if let Some(reason) = status.container_statuses.unwrap_or_default().iter().find_map(fatal_waiting_reason) {
+                break reason;
+            }
+            if Instant::now() >= deadline {
+                break format!("did not start within {:?}, see --relay-timeout", start_timeout);
+            }
+            sleep(START_POLL_INTERVAL).await;
+        };
+        relay.delete().await.ok();
+        Err(Error::RelayError(format!("Relay pod {} failed to start: {}", relay.name, reason)))
+    }
+
+    // Adds container sharing network namespace of the pod, returns its name
+    pub async fn create_ephemeral_container(&self, context: String, ns: String, pod: String, image: String) -> Result<String, Error> {
+        let client = self.get_client(context).await?;
+        let version = client.apiserver_version().await?;
+        if !supports_ephemeral_containers(&version.major, &version.minor) {
+            let (major, minor) = EPHEMERAL_CONTAINERS_MIN_VERSION;
+            return Err(Error::EphemeralContainerError(format!("Ephemeral containers require Kubernetes {}.{} or newer, cluster runs {}.{}", major, minor, version.major, version.minor)));
+        }
+        let pods = Api::<Pod>::namespaced(client, &ns);
+
+        // ephemeral containers could not be removed, so reuse running one
+        let statuses = pods.get(&pod).await?.status.and_then(|s| s.ephemeral_container_statuses).unwrap_or_default();
+        let running = statuses.iter().find(|s| {
+            s.name.starts_with(EPHEMERAL_CONTAINER_PREFIX) && s.image == image && s.state.as_ref().and_then(|s| s.running.as_ref()).is_some()
+        });
+        if let Some(status) = running {
+            log::info!("Reusing ephemeral container: {}", status.name);
+            return Ok(status.name.clone());
+        }
+
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let name = format!("{}-{:x}", EPHEMERAL_CONTAINER_PREFIX, started);
+        let patch = serde_json::json!({
+            "spec": {
+                "ephemeralContainers": [{
+                    "name": name,
+                    "image": image,
+                    "command": ["sleep", RELAY_LIFETIME.to_string()],
+                }]
+            }
+        });
+        pods.patch_subresource("ephemeralcontainers", &pod, &PatchParams::default(), &Patch::Strategic(patch)).await?;
+        log::info!("Created ephemeral container: {}/{}/{}", ns, pod, name);
+
+        let deadline = Instant::now() + EPHEMERAL_START_TIMEOUT;
+        let reason = loop {
+            let statuses = pods.get(&pod).await?.status.and_then(|s| s.ephemeral_container_statuses).unwrap_or_default();
+            let status = statuses.into_iter().find(|s| s.name == name);
+            match status.as_ref().and_then(|s| s.state.as_ref()) {
+                Some(state) if state.running.is_some() => return Ok(name),
+                Some(state) if state.terminated.is_some() => break "exited".to_string(),
+                _ => {},
+            }
+            if let Some(reason) = status.as_ref().and_then(fatal_waiting_reason) {
+                break reason;
+            }
+            if Instant::now() >= deadline {
+                break format!("did not start within {:?}", EPHEMERAL_START_TIMEOUT);
+            }
+            sleep(START_POLL_INTERVAL).await;
+        };
+        Err(Error::EphemeralContainerError(format!("Ephemeral container {} failed to start: {}", name, reason)))
+    }
     
 }
 
 impl KubeConfigs {
-    pub async fn get_connections(&self, context: String, ns: String, pod: String, agent: &[String]) -> Result<Receiver<impl AsyncRead + AsyncWrite + Unpin + Send + 'static>, Error> {
+    // Connections accepted by the agent, tagged with the port they were accepted on
+    pub async fn get_connections(&self, context: String, ns: String, pod: String, container: Option<String>, session: &AgentSession, args: &[&str]) -> Result<Receiver<(DuplexStream, String)>, Error> {
+        let (reader, writer, proc) = self.start_agent(context, ns, pod.clone(), container, session, args, FEATURE_PORT_TAGS).await?;
+        let mux = Multiplexer::new();
+        stop_agent_on_shutdown(session, pod, mux.shutdown_handle(), proc);
+        Ok(mux.accept_destinations(reader, writer))
+    }
+
+    // Connections to destinations the agent connects to
+    pub async fn open_connections(&self, context: String, ns: String, pod: String, container: Option<String>, session: &AgentSession, args: &[&str]) -> Result<MuxConnector, Error> {
+        let (reader, writer, proc) = self.start_agent(context, ns, pod.clone(), container, session, args, FEATURE_DYNAMIC_DESTINATIONS).await?;
+        let mux = Multiplexer::new();
+        stop_agent_on_shutdown(session, pod, mux.shutdown_handle(), proc);
+        Ok(mux.open_connections(reader, writer))
+    }
+
+    // Runs agent multiplexing connections over its stdio
+    #[allow(clippy::too_many_arguments)]
+    async fn start_agent(&self, context: String, ns: String, pod: String, container: Option<String>, session: &AgentSession, args: &[&str], features: u8) -> Result<(impl AsyncRead + Unpin + Send + 'static, impl AsyncWrite + Unpin + Send + 'static, AttachedProcess), Error> {
+        let agent = installed_agent_command(session, &context, &ns, &pod, &container, args)?;
         let client = self.get_client(context).await?;
         let pods = Api::<Pod>::namespaced(client, &ns);
         let mut params = AttachParams::default();
         params.stderr = true;
         params.stdin = true;
         params.stdout = true;
-        let mut proc = pods.exec(&pod, agent, &params).await?;
+        params.container = container;
+        let mut proc = pods.exec(&pod, agent.cmd, &params).await?;
+        let mut writer = proc.stdin().expect("Remote stdin failed");
+        if let Some(preload) = agent.preload {
+            writer.write_all(preload).await.or(Err(Error::ExecError("Failed to upload agent".to_string())))?;
+        }
         
         let mut stderr_stream = proc.stderr().expect("Remote stderr failed");
         tokio::spawn(async move {
             copy(&mut stderr_stream, &mut stderr()).await
         });
-        let cons = Multiplexer::new().produce_connections(proc.stdout().expect("Remote stdout failed"), proc.stdin().expect("Remote stdin failed"));
-        // kill switch of remote agent
+        let mut reader = proc.stdout().expect("Remote stdout failed");
+        handshake(&mut reader, &mut writer, features).await?;
+        Ok((reader, writer, proc))
+    }
+
+    pub async fn connect(&self, context: String, ns: String, pod: String, container: Option<String>, session: &AgentSession, args: &[&str]) -> Result<ExecPipeEndpoint, Error> {
+        let target = agent_target(&context, &ns, &pod, &container);
+        // agent run from memory is uploaded once and connects to every destination
+        if session.runs_from_memory(&target) {
+            let destination = dynamic_destination(args).ok_or(Error::ExecError(format!("Agent could not connect to {}", args.join(" "))))?;
+            let start = self.open_connections(context, ns, pod, container, session, &["--dynamic"]);
+            let connector = session.get_connector(&target, start).await?;
+            let stream = socks::dial(&connector, &destination).await
+                .map_err(|status| Error::ExecError(format!("Failed to connect to {}: reply {}", destination, status)))?;
+            let (reader, writer) = split(stream);
+            return Ok(ExecPipeEndpoint { reader: Box::new(reader), writer: Box::new(writer) });
+        }
+        let agent = installed_agent_command(session, &context, &ns, &pod, &container, args)?;
+        let mut con = self.attach(context, ns, pod, container, agent.cmd, agent.preload).await?;
+        handshake(&mut con.reader, &mut con.writer, 0).await?;
+        Ok(con)
+    }
+
+    // Process started with `sh -c` in the pod
+    pub async fn exec(&self, context: String, ns: String, pod: String, container: Option<String>, command: &str) -> Result<ExecPipeEndpoint, Error> {
+        let cmd = vec!["sh".to_string(), "-c".to_string(), command.to_string()];
+        self.attach(context, ns, pod, container, cmd, None).await
+    }
+
+    // Runs the command with stdin preloaded, its stderr goes to local stderr
+    async fn attach(&self, context: String, ns: String, pod: String, container: Option<String>, cmd: Vec<String>, preload: Option<&[u8]>) -> Result<ExecPipeEndpoint, Error> {
+        let client = self.get_client(context).await?;
+        let pods = Api::<Pod>::namespaced(client, &ns);
+        let mut params = AttachParams::default().stdin(true).stdout(true).stderr(true);
+        params.container = container;
+        let mut proc = pods.exec(&pod, cmd, &params).await?;
+        let mut writer = proc.stdin().expect("Remote stdin failed");
+        if let Some(preload) = preload {
+            writer.write_all(preload).await.or(Err(Error::ExecError("Failed to upload agent".to_string())))?;
+        }
+
+        let mut stderr_stream = proc.stderr().expect("Remote stderr failed");
         tokio::spawn(async move {
-            tokio::signal::ctrl_c().await.unwrap();
-            params.stderr = false;
-            params.stdin = false;
-            params.stdout = true;
-            let kill_proc = pods.exec(&pod, ["touch", AGENT_KILL_PATH], &params).await.unwrap();
-            kill_proc.join().await.unwrap();
-            exit(0);
+            copy(&mut stderr_stream, &mut stderr()).await
         });
+        let reader = proc.stdout().expect("Remote stdout failed");
+        // prevent process from being dropped
+        tokio::spawn(proc.join());
+
+        Ok(ExecPipeEndpoint { reader: Box::new(reader), writer: Box::new(writer) })
+    }
+}
 
-        Ok(cons)
+// Minor versions of managed clusters have suffixes, e.g. "23+"
+fn supports_ephemeral_containers(major: &str, minor: &str) -> bool {
+    let number = |v: &str| v.chars().take_while(char::is_ascii_digit).collect::<String>().parse::<u32>().ok();
+    match (number(major), number(minor)) {
+        (Some(major), Some(minor)) => (major, minor) >= EPHEMERAL_CONTAINERS_MIN_VERSION,
+        // unknown versions are tried
+        _ => true,
+    }
+}
+
+// Reason and message of a container stuck waiting, e.g. for an image that could not be pulled
+fn fatal_waiting_reason(status: &ContainerStatus) -> Option<String> {
+    let waiting = status.state.as_ref()?.waiting.as_ref()?;
+    let reason = waiting.reason.as_deref().filter(|r| FATAL_WAITING_REASONS.contains(r))?;
+    Some(match &waiting.message {
+        Some(message) => format!("{}: {}", reason, message),
+        None => reason.to_string(),
+    })
+}
+
+// Key of the agent installed into pod container
+fn agent_target(context: &str, ns: &str, pod: &str, container: &Option<String>) -> String {
+    format!("kube/{}/{}/{}/{}", context, ns, pod, container.clone().unwrap_or_default())
+}
+
+// Stops remote agent and waits for it to exit
+fn stop_agent_on_shutdown(session: &AgentSession, pod: String, agent_shutdown: MuxShutdown, proc: AttachedProcess) {
+    session.on_shutdown(format!("agent in pod {}", pod), async move {
+        agent_shutdown.shutdown().await;
+        timeout(Duration::from_secs(AGENT_SHUTDOWN_TIMEOUT), proc.join()).await
+            .or(Err("agent did not exit".to_string()))?
+            .map_err(|e| e.to_string())
+    });
+}
+
+fn installed_agent_command(session: &AgentSession, context: &str, ns: &str, pod: &str, container: &Option<String>, args: &[&str]) -> Result<AgentCommand, Error> {
+    session.get_agent_command(&agent_target(context, ns, pod, container), args)
+        .ok_or(Error::ExecError(format!("Agent is not installed in {}", pod)))
+}
+
+async fn memfd_supported(pods: &Api<Pod>, pod: &str, container: Option<String>) -> bool {
+    exec_output(pods, pod, container, &MEMFD_CHECK).await.is_ok()
+}
+
+// Writable emptyDir volumes mounted into the container
+async fn empty_dir_mounts(pods: &Api<Pod>, pod: &str, container: &Option<String>) -> Vec<String> {
+    let spec = match pods.get(pod).await {
+        Ok(Pod { spec: Some(spec), .. }) => spec,
+        _ => return vec![]
+    };
+    let volumes: Vec<String> = spec.volumes.unwrap_or_default().into_iter()
+        .filter(|v| v.empty_dir.is_some())
+        .map(|v| v.name)
+        .collect();
+    let mounts = match container {
+        Some(name) => spec.containers.into_iter().find(|c| &c.name == name).map(|c| c.volume_mounts)
+            .or_else(|| spec.ephemeral_containers.unwrap_or_default().into_iter().find(|c| &c.name == name).map(|c| c.volume_mounts)),
+        None => spec.containers.into_iter().next().map(|c| c.volume_mounts),
+    };
+    mounts.flatten().unwrap_or_default().into_iter()
+        .filter(|m| volumes.contains(&m.name) && !m.read_only.unwrap_or(false))
+        .map(|m| m.mount_path)
+        .collect()
+}
+
+// Runs command in the pod and returns its stdout
+async fn exec_output(pods: &Api<Pod>, pod: &str, container: Option<String>, cmd: &[&str]) -> Result<String, Error> {
+    exec_input(pods, pod, container, cmd, None).await
+}
+
+// Same as `exec_output`, writing the input to stdin of the command first
+async fn exec_input(pods: &Api<Pod>, pod: &str, container: Option<String>, cmd: &[&str], input: Option<&[u8]>) -> Result<String, Error> {
+    let mut params = AttachParams::default().stdin(input.is_some()).stdout(true).stderr(false);
+    params.container = container;
+    let mut proc = pods.exec(pod, cmd.to_vec(), &params).await?;
+    if let Some(input) = input {
+        let mut stdin = proc.stdin().ok_or(Error::ExecError(format!("Failed to exec {}", cmd[0])))?;
+        stdin.write_all(input).await.or(Err(Error::ExecError(format!("Failed to write to {}", cmd[0]))))?;
+        stdin.shutdown().await.or(Err(Error::ExecError(format!("Failed to write to {}", cmd[0]))))?;
+    }
+    let mut stdout = proc.stdout().ok_or(Error::ExecError(format!("Failed to exec {}", cmd[0])))?;
+    let mut out = String::new();
+    stdout.read_to_string(&mut out).await.or(Err(Error::ExecError(format!("Failed to exec {}", cmd[0]))))?;
+    if let Some(status) = proc.take_status() {
+        if let Some(status) = status.await {
+            if status.status.as_deref() != Some("Success") {
+                return Err(Error::ExecError(status.message.unwrap_or(format!("Failed to exec {}", cmd[0]))));
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Pod created to reach cluster addresses when exec into workloads is not possible
+#[derive(Clone)]
+pub struct RelayPod {
+    pods: Api<Pod>,
+    name: String
+}
+
+impl RelayPod {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn delete(&self) -> Result<(), Error> {
+        let params = DeleteParams { grace_period_seconds: Some(0), ..Default::default() };
+        self.pods.delete(&self.name, &params).await?;
+        log::info!("Deleted relay pod: {}", self.name);
+        Ok(())
+    }
+}
+
+// Stdin and stdout of a process running in a pod
+pub struct ExecPipeEndpoint {
+    reader: Box<dyn AsyncRead + Unpin + Send + 'static>,
+    writer: Box<dyn AsyncWrite + Unpin + Send + 'static>
+}
+
+impl PipeEndpoint for ExecPipeEndpoint {
+    fn get_sink_and_source(self: Box<Self>) -> (Box<dyn AsyncRead + Unpin + Send + 'static>, Box<dyn AsyncWrite + Unpin + Send + 'static>) {
+        (self.reader, self.writer)
     }
 }
 
@@ -327,3 +663,32 @@ impl PipeEndpoint for PortForwardPipeEndpoint {
         return (Box::new(stream), Box::new(sink));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::{ContainerState, ContainerStateWaiting};
+
+    use super::*;
+
+    #[test]
+    fn image_pull_failures_are_fatal() {
+        let waiting = |reason: &str| ContainerStatus {
+            state: Some(ContainerState {
+                waiting: Some(ContainerStateWaiting { reason: Some(reason.to_string()), message: Some("busybox:nope not found".to_string()) }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(fatal_waiting_reason(&waiting("ImagePullBackOff")).as_deref(), Some("ImagePullBackOff: busybox:nope not found"));
+        assert_eq!(fatal_waiting_reason(&waiting("ContainerCreating")), None);
+        assert_eq!(fatal_waiting_reason(&ContainerStatus::default()), None);
+    }
+
+    #[test]
+    fn ephemeral_containers_need_1_23() {
+        assert!(supports_ephemeral_containers("1", "23+"));
+        assert!(supports_ephemeral_containers("1", "27"));
+        assert!(!supports_ephemeral_containers("1", "22"));
+        assert!(supports_ephemeral_containers("", ""));
+    }
+}
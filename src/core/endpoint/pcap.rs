@@ -0,0 +1,192 @@
+use std::{fs::File, io::Write, net::{IpAddr, Ipv4Addr, SocketAddr}, sync::atomic::{AtomicU64, Ordering}, time::{SystemTime, UNIX_EPOCH}};
+
+use super::{ConnectionAddresses, ConnectionTap, Direction, Tap, TapWriter};
+
+// Packets are IPv4 or IPv6 packets without link layer headers
+static LINKTYPE_RAW: u32 = 101;
+static SNAPLEN: u32 = 65535;
+// Payload of a single synthesized segment
+static SEGMENT_SIZE: usize = 16 * 1024;
+// Connections without addresses come from these ports on 127.0.0.1 to port 0
+static FIRST_PORT: u16 = 49152;
+static PORTS: u64 = 16384;
+
+static FIN: u8 = 0x01;
+static SYN: u8 = 0x02;
+static PSH: u8 = 0x08;
+static ACK: u8 = 0x10;
+
+// Records forwarded connections as TCP flows, ORIGIN is the client
+pub struct PcapWriter {
+    writer: TapWriter,
+    connections: AtomicU64
+}
+
+impl PcapWriter {
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(&header())?;
+        Ok(PcapWriter { writer: TapWriter::spawn(tokio::fs::File::from_std(file)), connections: AtomicU64::new(0) })
+    }
+}
+
+impl Tap for PcapWriter {
+    fn open(&self, addresses: Option<ConnectionAddresses>) -> Box<dyn ConnectionTap> {
+        let id = self.connections.fetch_add(1, Ordering::Relaxed);
+        let addresses = addresses.unwrap_or(ConnectionAddresses {
+            peer: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), FIRST_PORT + (id % PORTS) as u16),
+            local: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)
+        });
+        let mut flow = Flow { writer: self.writer.clone(), addresses, seq: [0, 0] };
+        flow.segment(Direction::Outbound, SYN, &[]);
+        flow.segment(Direction::Inbound, SYN | ACK, &[]);
+        flow.segment(Direction::Outbound, ACK, &[]);
+        Box::new(flow)
+    }
+
+    fn writer(&self) -> &TapWriter {
+        &self.writer
+    }
+}
+
+struct Flow {
+    writer: TapWriter,
+    addresses: ConnectionAddresses,
+    // next sequence numbers outbound and inbound
+    seq: [u32; 2]
+}
+
+impl Flow {
+    fn segment(&mut self, direction: Direction, flags: u8, payload: &[u8]) {
+        let (src, dst, side) = match direction {
+            Direction::Outbound => (self.addresses.peer, self.addresses.local, 0),
+            Direction::Inbound => (self.addresses.local, self.addresses.peer, 1),
+        };
+        let ack = if flags & ACK != 0 { self.seq[1 - side] } else { 0 };
+        let packet = packet(src, dst, self.seq[side], ack, flags, payload);
+        let consumed = if flags & (SYN | FIN) != 0 { 1 } else { 0 };
+        self.seq[side] = self.seq[side].wrapping_add(payload.len() as u32 + consumed);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+        self.writer.write(record);
+    }
+}
+
+impl ConnectionTap for Flow {
+    fn data(&mut self, direction: Direction, bytes: &[u8]) {
+        for payload in bytes.chunks(SEGMENT_SIZE) {
+            self.segment(direction, PSH | ACK, payload);
+        }
+    }
+
+    fn close(&mut self) {
+        self.segment(Direction::Outbound, FIN | ACK, &[]);
+        self.segment(Direction::Inbound, FIN | ACK, &[]);
+        self.segment(Direction::Outbound, ACK, &[]);
+    }
+}
+
+fn header() -> Vec<u8> {
+    let mut header = vec![];
+    header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    // time zone and timestamp accuracy
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+// IP packet carrying a TCP segment, addresses of different families are sent as IPv6
+fn packet(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut tcp = vec![];
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    // header of 5 words, flags, window, checksum and urgent pointer
+    tcp.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+
+    let (mut packet, mut pseudo) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let mut ip = vec![0x45, 0];
+            ip.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+            // no fragments, TTL 64, TCP
+            ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            ip.extend_from_slice(&s.octets());
+            ip.extend_from_slice(&d.octets());
+            let sum = checksum(&ip);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            let mut pseudo = [s.octets(), d.octets()].concat();
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+            (ip, pseudo)
+        },
+        (s, d) => {
+            let (s, d) = (to_ipv6(s), to_ipv6(d));
+            let mut ip = vec![0x60, 0, 0, 0];
+            ip.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+            // TCP, hop limit 64
+            ip.extend_from_slice(&[6, 64]);
+            ip.extend_from_slice(&s);
+            ip.extend_from_slice(&d);
+            let mut pseudo = [s, d].concat();
+            pseudo.extend_from_slice(&(tcp.len() as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, 6]);
+            (ip, pseudo)
+        },
+    };
+    pseudo.extend_from_slice(&tcp);
+    let sum = checksum(&pseudo);
+    tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+// Internet checksum, ones' complement of the ones' complement sum of 16 bit words
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data.chunks(2).map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32).sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_have_valid_checksums() {
+        assert_eq!(header().len(), 24);
+
+        let client: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let server: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let ip = packet(client, server, 1, 2, PSH | ACK, b"hello");
+        assert_eq!(ip.len(), 20 + 20 + 5);
+        assert_eq!(checksum(&ip[..20]), 0);
+        let mut pseudo = vec![127, 0, 0, 1, 127, 0, 0, 1, 0, 6, 0, 25];
+        pseudo.extend_from_slice(&ip[20..]);
+        assert_eq!(checksum(&pseudo), 0);
+        assert_eq!(&ip[20..24], &[0xc3, 0x50, 0x1f, 0x90]);
+
+        let ip = packet("[::1]:50000".parse().unwrap(), server, 0, 0, SYN, &[]);
+        assert_eq!(ip.len(), 40 + 20);
+        assert_eq!(&ip[24..40], &Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets());
+    }
+}
@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, duplex, split};
+use tokio::sync::mpsc::channel;
+use tokio::time::{Instant, sleep, sleep_until};
+use tokio_util::sync::CancellationToken;
+
+use super::PipeEndpoint;
+
+static BUFFER_SIZE: usize = 16 * 1024;
+// Chunks in flight in each direction
+static QUEUE_SIZE: usize = 1024;
+
+// Faults added to each direction of a connection, rates are probabilities per chunk of data read
+#[derive(Debug, Clone, Default)]
+pub struct Shaping {
+    pub latency: Duration,
+    // latency varies up to this much either way
+    pub jitter: Duration,
+    // bytes per second
+    pub bandwidth: Option<u64>,
+    // connection is closed without delivering data in flight
+    pub drop_rate: f64,
+    pub stall_rate: f64,
+    pub stall: Duration
+}
+
+impl Shaping {
+    pub fn is_active(&self) -> bool {
+        !self.latency.is_zero() || !self.jitter.is_zero() || self.bandwidth.is_some() || self.drop_rate > 0.0 || self.stall_rate > 0.0
+    }
+
+    fn delay(&self) -> Duration {
+        let jitter = self.jitter.as_secs_f64() * (2.0 * random() - 1.0);
+        Duration::from_secs_f64((self.latency.as_secs_f64() + jitter).max(0.0))
+    }
+}
+
+// Uniform in [0, 1)
+fn random() -> f64 {
+    let mut bytes = [0u8; 8];
+    _ = openssl::rand::rand_bytes(&mut bytes);
+    (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Spreads bytes over time so they do not go faster than the rate, idle time is not saved up
+pub struct RateLimiter {
+    // bytes per second
+    rate: u64,
+    next: Instant
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        RateLimiter { rate: rate.max(1), next: Instant::now() }
+    }
+
+    // Waits until the bytes could have been sent
+    pub async fn wait(&mut self, size: usize) {
+        self.next = self.next.max(Instant::now()) + Duration::from_secs_f64(size as f64 / self.rate as f64);
+        sleep_until(self.next).await;
+    }
+}
+
+// Endpoint adding faults to the data going to and coming from another one
+pub struct ShapedPipeEndpoint {
+    inner: Box<dyn PipeEndpoint>,
+    shaping: Shaping
+}
+
+impl ShapedPipeEndpoint {
+    pub fn new(inner: Box<dyn PipeEndpoint>, shaping: Shaping) -> Self {
+        ShapedPipeEndpoint { inner, shaping }
+    }
+}
+
+impl PipeEndpoint for ShapedPipeEndpoint {
+    fn get_sink_and_source(self: Box<Self>) -> (Box<dyn AsyncRead + Unpin + Send + 'static>, Box<dyn AsyncWrite + Unpin + Send + 'static>) {
+        let (outer, local) = duplex(BUFFER_SIZE);
+        let (source, sink) = self.inner.get_sink_and_source();
+        let (local_source, local_sink) = split(local);
+        // dropping the connection stops both directions
+        let dropped = CancellationToken::new();
+        tokio::spawn(shape(local_source, sink, self.shaping.clone(), dropped.clone()));
+        tokio::spawn(shape(source, local_sink, self.shaping, dropped));
+        let (reader, writer) = split(outer);
+        (Box::new(reader), Box::new(writer))
+    }
+}
+
+// Copies from reader to writer, chunks are read as they come and written when their delay passes
+async fn shape(mut reader: impl AsyncRead + Unpin, mut writer: impl AsyncWrite + Unpin, shaping: Shaping, dropped: CancellationToken) {
+    let (tx, mut rx) = channel::<(Instant, Vec<u8>)>(QUEUE_SIZE);
+    let read = {
+        let shaping = shaping.clone();
+        let dropped = dropped.clone();
+        async move {
+            let mut buf = vec![0u8; BUFFER_SIZE];
+            let mut last = Instant::now();
+            loop {
+                let len = match reader.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => len,
+                };
+                if random() < shaping.drop_rate {
+                    log::info!("Dropping connection");
+                    dropped.cancel();
+                    break;
+                }
+                // jitter does not reorder data
+                last = last.max(Instant::now() + shaping.delay());
+                if tx.send((last, buf[..len].to_vec())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    };
+    let write = async move {
+        let mut limiter = shaping.bandwidth.map(RateLimiter::new);
+        while let Some((at, bytes)) = rx.recv().await {
+            sleep_until(at).await;
+            if random() < shaping.stall_rate {
+                sleep(shaping.stall).await;
+            }
+            if let Some(limiter) = &mut limiter {
+                limiter.wait(bytes.len()).await;
+            }
+            if writer.write_all(&bytes).await.is_err() {
+                return;
+            }
+        }
+        _ = writer.shutdown().await;
+    };
+    tokio::select! {
+        _ = futures::future::join(read, write) => {},
+        _ = dropped.cancelled() => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn shaping_delays_limits_and_drops() {
+        let (inner, mut peer) = duplex(BUFFER_SIZE);
+        let shaping = Shaping { latency: Duration::from_millis(50), bandwidth: Some(10_000), ..Default::default() };
+        let (mut source, mut sink) = Box::new(ShapedPipeEndpoint::new(Box::new(inner), shaping)).get_sink_and_source();
+        let start = Instant::now();
+        sink.write_all(&[1; 1000]).await.unwrap();
+        let mut buf = [0u8; 1000];
+        peer.read_exact(&mut buf).await.unwrap();
+        // 50ms latency and 100ms for 1000 bytes at 10000 bytes per second
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        let (inner, mut dropping_peer) = duplex(BUFFER_SIZE);
+        let shaping = Shaping { drop_rate: 1.0, ..Default::default() };
+        let (mut dropping_source, _sink) = Box::new(ShapedPipeEndpoint::new(Box::new(inner), shaping)).get_sink_and_source();
+        dropping_peer.write_all(b"lost").await.unwrap();
+        assert_eq!(dropping_source.read(&mut buf).await.unwrap(), 0);
+
+        drop(peer);
+        drop(sink);
+        assert_eq!(source.read(&mut buf).await.unwrap(), 0);
+    }
+}
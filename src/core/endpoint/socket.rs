@@ -1,29 +1,164 @@
+use std::{net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr}, process::exit, fs::Permissions, ops::Range, os::{linux::net::SocketAddrExt, unix::{self, fs::{FileTypeExt, PermissionsExt}, io::FromRawFd, net::UnixDatagram}}};
 
-use std::{net::SocketAddr, process::exit};
+use futures::future::select_all;
+use tokio::{net::{TcpListener, TcpStream, UnixListener, UnixStream}};
+
+// Addresses `localhost` stands for
+pub fn localhost(port: u16) -> Vec<SocketAddr> {
+    vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port), SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port)]
+}
 
-use tokio::{net::{TcpListener, TcpSocket, TcpStream}};
 pub struct TCPConnectionProvider {
-    address: SocketAddr
+    addresses: Vec<SocketAddr>
+}
+
+// Listeners accepting connections on all addresses of a provider
+pub struct TcpListeners {
+    listeners: Vec<TcpListener>
+}
+
+impl TcpListeners {
+    pub async fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
+        select_all(self.listeners.iter().map(|l| Box::pin(l.accept()))).await.0
+    }
+
+    // Addresses listened on, with the port picked when it was 0
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().filter_map(|l| l.local_addr().ok()).collect()
+    }
+
+    // Listening sockets passed by systemd socket activation, none when they were passed to another process.
+    // Like sd_listen_fds(1) the variables are unset, so processes started later do not take the sockets too
+    pub fn activated() -> Option<Self> {
+        let fds = activated_fds(std::env::var("LISTEN_PID").ok().as_deref(), std::env::var("LISTEN_FDS").ok().as_deref(), std::process::id());
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(name);
+        }
+        if fds.is_empty() {
+            return None;
+        }
+        match Self::adopt(fds) {
+            Ok(listeners) => Some(listeners),
+            Err((fd, e)) => {
+                eprintln!("Error: socket {} passed by systemd is not a TCP listener: {e}", fd);
+                exit(1);
+            },
+        }
+    }
+
+    // Takes over inherited listening sockets, they are duplicated to be closed on exec like the ones opened here
+    fn adopt(fds: impl Iterator<Item = i32>) -> Result<Self, (i32, std::io::Error)> {
+        let mut listeners = vec![];
+        for fd in fds {
+            // the descriptors are owned by this process from now on, the inherited one is closed once duplicated
+            let inherited = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            let listener = inherited.try_clone()
+                .and_then(|l| l.set_nonblocking(true).and_then(|_| l.local_addr()).map(|_| l))
+                .and_then(TcpListener::from_std)
+                .map_err(|e| (fd, e))?;
+            listeners.push(listener);
+        }
+        Ok(TcpListeners { listeners })
+    }
+
+    // Sockets for the ports of a range, all of them for a single port or one for each port in order
+    pub fn per_port(self, ports: usize) -> Result<Vec<TcpListeners>, String> {
+        if ports == 1 {
+            return Ok(vec![self]);
+        }
+        if self.listeners.len() != ports {
+            return Err(format!("systemd passed {} sockets for a range of {} ports, one for each port is needed", self.listeners.len(), ports));
+        }
+        Ok(self.listeners.into_iter().map(|l| TcpListeners { listeners: vec![l] }).collect())
+    }
+
+    // Sockets passed by systemd are used as they are, addresses differing from ORIGIN are only reported
+    pub fn warn_unless_listening_on(&self, origin: &[SocketAddr]) {
+        for addr in self.local_addrs() {
+            if !origin.iter().any(|o| same_listen_address(addr, *o)) {
+                eprintln!("Warning: socket passed by systemd listens on {}, not on ORIGIN", addr);
+            }
+        }
+    }
+}
+
+// Loopback and unspecified addresses of both families stand for the same ORIGIN
+fn same_listen_address(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() == b.port() && (a.ip() == b.ip() || (a.ip().is_loopback() && b.ip().is_loopback()) || (a.ip().is_unspecified() && b.ip().is_unspecified()))
+}
+
+// First descriptor passed by systemd, the ones before are stdin, stdout and stderr
+static LISTEN_FDS_START: i32 = 3;
+
+fn activated_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Range<i32> {
+    match (listen_pid.and_then(|p| p.parse::<u32>().ok()), listen_fds.and_then(|n| n.parse::<i32>().ok())) {
+        (Some(listen_pid), Some(n)) if listen_pid == pid && n > 0 => LISTEN_FDS_START..LISTEN_FDS_START + n,
+        _ => LISTEN_FDS_START..LISTEN_FDS_START,
+    }
+}
+
+// Sends the state to the service manager, e.g. READY=1, when running under systemd with Type=notify
+pub fn notify_systemd(state: &str) {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::info!("Failed to notify systemd: {}", e);
+            return;
+        },
+    };
+    // sockets starting with @ are in the abstract namespace
+    let sent = match path.strip_prefix('@') {
+        Some(name) => unix::net::SocketAddr::from_abstract_name(name).and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr)),
+        None => socket.send_to(state.as_bytes(), &path),
+    };
+    if let Err(e) = sent {
+        log::info!("Failed to notify systemd: {}", e);
+    }
 }
 
 impl TCPConnectionProvider {
     pub fn new(address: SocketAddr) -> Self {
-        TCPConnectionProvider { address: address }
+        TCPConnectionProvider { addresses: vec![address] }
+    }
+
+    // Addresses are tried in order when connecting
+    pub fn from_addresses(addresses: Vec<SocketAddr>) -> Self {
+        TCPConnectionProvider { addresses }
     }
 
-    pub async fn listen_for_connections(self) -> TcpListener {
-        match TcpListener::bind(self.address).await {
+    // First address has to be available, the other ones are skipped when the host lacks them,
+    // e.g. IPv6 is disabled. They share the port picked for the first one
+    pub async fn listen_for_connections(self) -> TcpListeners {
+        let mut addresses = self.addresses.into_iter();
+        let first = match addresses.next().map(TcpListener::bind) {
+            Some(bind) => bind.await,
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to listen on")),
+        };
+        let first = match first {
             Ok(l) => l,
             Err(e) => {
                 eprintln!("Error: {e}");
                 exit(1);
             },
+        };
+        let port = first.local_addr().map(|a| a.port()).unwrap_or_default();
+        let mut listeners = vec![first];
+        for mut address in addresses {
+            address.set_port(port);
+            match TcpListener::bind(address).await {
+                Ok(l) => listeners.push(l),
+                Err(e) => log::info!("Not listening on {}: {}", address, e),
+            }
         }
+        TcpListeners { listeners }
     }
     
     pub async fn connect(self) -> TcpStream {
-        let soc = TcpSocket::new_v4().unwrap();
-        match soc.connect(self.address).await {
+        match TcpStream::connect(&self.addresses[..]).await {
             Ok(c) => c,
             Err(e) => {
                 eprintln!("Error: {e}");
@@ -32,3 +167,102 @@ impl TCPConnectionProvider {
         }
     } 
 }
+
+pub struct UnixConnectionProvider {
+    path: String,
+    mode: Option<u32>
+}
+
+impl UnixConnectionProvider {
+    pub fn new(path: String, mode: Option<u32>) -> Self {
+        UnixConnectionProvider { path, mode }
+    }
+
+    // Replaces stale socket left by a process that is gone, refuses sockets in use and other files
+    pub async fn listen_for_connections(self) -> UnixListener {
+        if let Ok(meta) = std::fs::symlink_metadata(&self.path) {
+            if !meta.file_type().is_socket() {
+                eprintln!("Error: {} exists and is not a socket", self.path);
+                exit(1);
+            }
+            if UnixStream::connect(&self.path).await.is_ok() {
+                eprintln!("Error: {} is in use", self.path);
+                exit(1);
+            }
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                eprintln!("Error: failed to remove stale socket {}: {e}", self.path);
+                exit(1);
+            }
+        }
+        let listener = match UnixListener::bind(&self.path) {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("Error: {}: {e}", self.path);
+                exit(1);
+            },
+        };
+        if let Some(mode) = self.mode {
+            if let Err(e) = std::fs::set_permissions(&self.path, Permissions::from_mode(mode)) {
+                eprintln!("Error: failed to set mode of {}: {e}", self.path);
+                exit(1);
+            }
+        }
+        listener
+    }
+
+    pub async fn connect(self) -> UnixStream {
+        match UnixStream::connect(&self.path).await {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Error: {}: {e}", self.path);
+                exit(1);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+    use super::*;
+
+    #[test]
+    fn activated_fds_belong_to_this_process() {
+        assert_eq!(activated_fds(Some("42"), Some("2"), 42), 3..5);
+        assert!(activated_fds(Some("41"), Some("2"), 42).is_empty());
+        assert!(activated_fds(None, Some("2"), 42).is_empty());
+        assert!(activated_fds(Some("42"), Some("0"), 42).is_empty());
+    }
+
+    #[tokio::test]
+    async fn adopted_sockets_are_closed_on_exec() {
+        let inherited: Vec<i32> = (0..2).map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap().into_raw_fd()).collect();
+        let listeners = TcpListeners::adopt(inherited.iter().copied()).unwrap();
+        assert_eq!(listeners.local_addrs().len(), 2);
+        for listener in &listeners.listeners {
+            let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", listener.as_raw_fd())).unwrap();
+            let flags = fdinfo.lines().find_map(|l| l.strip_prefix("flags:")).map(|f| u32::from_str_radix(f.trim(), 8).unwrap()).unwrap();
+            // O_CLOEXEC
+            assert_ne!(flags & 0o2000000, 0);
+        }
+        let addr = listeners.local_addrs()[0];
+        assert!(same_listen_address(addr, SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())));
+        assert!(!same_listen_address(addr, SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr.port())));
+        assert!(!same_listen_address(addr, SocketAddr::new(addr.ip(), addr.port().wrapping_add(1))));
+    }
+
+    #[tokio::test]
+    async fn activated_sockets_are_taken_by_ports_of_a_range_in_order() {
+        let inherited: Vec<std::net::TcpListener> = (0..3).map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        let addrs: Vec<SocketAddr> = inherited.iter().map(|l| l.local_addr().unwrap()).collect();
+        let adopt = |listeners: &[std::net::TcpListener]| TcpListeners::adopt(listeners.iter().map(|l| l.try_clone().unwrap().into_raw_fd())).unwrap();
+
+        let range = adopt(&inherited).per_port(3).unwrap();
+        assert_eq!(range.iter().map(|l| l.local_addrs()).collect::<Vec<_>>(), addrs.iter().map(|a| vec![*a]).collect::<Vec<_>>());
+        let single = adopt(&inherited).per_port(1).unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].local_addrs(), addrs);
+        assert!(adopt(&inherited).per_port(2).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::mux::MuxConnector;
+
+// SOCKS5 (RFC 1928) server side, only CONNECT without authentication is supported
+static VERSION: u8 = 5;
+static NO_AUTHENTICATION: u8 = 0;
+static NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+static CONNECT: u8 = 1;
+
+pub static SUCCEEDED: u8 = 0;
+pub static GENERAL_FAILURE: u8 = 1;
+pub static HOST_UNREACHABLE: u8 = 4;
+pub static CONNECTION_REFUSED: u8 = 5;
+static COMMAND_NOT_SUPPORTED: u8 = 7;
+pub static ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    IoError(String),
+    UnsupportedVersion(u8),
+    UnsupportedMethods,
+    UnsupportedCommand(u8),
+    UnsupportedAddress(u8)
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IoError(e.to_string())
+    }
+}
+
+// Negotiates with the client and returns requested destination as host:port,
+// domain names are left for the other side to resolve
+pub async fn accept(con: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> Result<String, Error> {
+    let version = con.read_u8().await?;
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let mut methods = vec![0u8; con.read_u8().await? as usize];
+    con.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        con.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(Error::UnsupportedMethods);
+    }
+    con.write_all(&[VERSION, NO_AUTHENTICATION]).await?;
+
+    let mut request = [0u8; 4];
+    con.read_exact(&mut request).await?;
+    let [version, command, _, address_type] = request;
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let host = match address_type {
+        1 => {
+            let mut ip = [0u8; 4];
+            con.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        },
+        3 => {
+            let mut name = vec![0u8; con.read_u8().await? as usize];
+            con.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).to_string()
+        },
+        4 => {
+            let mut ip = [0u8; 16];
+            con.read_exact(&mut ip).await?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        },
+        _ => {
+            reply(con, ADDRESS_NOT_SUPPORTED).await?;
+            return Err(Error::UnsupportedAddress(address_type));
+        }
+    };
+    let port = con.read_u16().await?;
+    if command != CONNECT {
+        reply(con, COMMAND_NOT_SUPPORTED).await?;
+        return Err(Error::UnsupportedCommand(command));
+    }
+    Ok(format!("{}:{}", host, port))
+}
+
+// Opens connection to the destination through the agent, fails with SOCKS reply code,
+// general failure when the agent went away, unreachable or refused as reported by the agent
+pub async fn dial(connector: &MuxConnector, destination: &str) -> Result<DuplexStream, u8> {
+    let mut stream = connector.open(destination).await.ok_or(GENERAL_FAILURE)?;
+    // agent answers with the result of connecting to the destination
+    match stream.read_u8().await.unwrap_or(GENERAL_FAILURE) {
+        status if status == SUCCEEDED => Ok(stream),
+        status => Err(status),
+    }
+}
+
+// Bound address is not known locally, clients do not use it for CONNECT
+pub async fn reply(con: &mut (impl AsyncWrite + Unpin), code: u8) -> Result<(), Error> {
+    con.write_all(&[VERSION, code, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    con.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn accepts_connect_to_domain() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[5, 1, 0, 5, 1, 0, 3, 8]).await.unwrap();
+        client.write_all(b"kube-dns").await.unwrap();
+        client.write_all(&[0x15, 0x38]).await.unwrap();
+        let mut buf = [0u8; 2];
+        let destination = accept(&mut server).await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 0]);
+        assert_eq!(destination, "kube-dns:5432");
+    }
+}
@@ -1,9 +1,10 @@
 
 use std::{os::unix::prelude::FromRawFd, net::SocketAddr, process::{exit, Stdio}, path::Path, str::FromStr};
 
-use tokio::{io::{stdin, stdout, AsyncReadExt}, fs::File, process::Command};
+use futures::future::join_all;
+use tokio::{io::{stdin, stdout, AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt}, fs::File, process::{Command, ChildStdin, ChildStdout}, net::{TcpStream, UnixStream}, sync::{mpsc::Sender, oneshot}};
 
-use super::{PipeEndpoint, socket::TCPConnectionProvider, connect, PipeCopyDestination, PipeCopySource};
+use super::{PipeEndpoint, socket::{localhost, TCPConnectionProvider, TcpListeners, UnixConnectionProvider}, connect, PipeCopyDestination, PipeCopySource, socks, UNIX_DESTINATION_PREFIX};
 use crate::mux::Multiplexer;
 
 pub struct StdioPipeEndpoint;
@@ -26,24 +27,145 @@ impl PipeEndpoint for StdioPipeEndpoint {
     }
 }
 
-pub async fn multiplex_con_to_stdio(addr: SocketAddr) {
-    let socket = TCPConnectionProvider::new(addr).listen_for_connections().await;
+// Stdin and stdout of a local process, its stderr is inherited
+pub struct ProcessPipeEndpoint {
+    stdin: ChildStdin,
+    stdout: ChildStdout
+}
+
+impl ProcessPipeEndpoint {
+    // Starts the command with `sh -c`
+    pub fn spawn(command: &str) -> Result<Self, Error> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or(Error::ExecError("Failed to get process stdin".to_owned()))?;
+        let stdout = child.stdout.take().ok_or(Error::ExecError("Failed to get process stdout".to_owned()))?;
+        // reap the process once it exits
+        tokio::spawn(async move { child.wait().await });
+        Ok(ProcessPipeEndpoint { stdin, stdout })
+    }
+}
+
+impl PipeEndpoint for ProcessPipeEndpoint {
+    fn get_sink_and_source(self: Box<Self>) -> (Box<dyn AsyncRead + Unpin + Send + 'static>, Box<dyn AsyncWrite + Unpin + Send + 'static>) {
+        (Box::new(self.stdout), Box::new(self.stdin))
+    }
+}
+
+// Multiplexes connections sent to the returned channel with their tag over stdio
+fn stdio_multiplexer<T>() -> (Sender<(T, String)>, oneshot::Receiver<()>)
+    where T: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
     let in_buffer = unsafe { File::from_raw_fd(0) }; //stdin
     let out_buffer = unsafe { File::from_raw_fd(1) }; //stdout
-    let mux = Multiplexer::new().consume_connections(in_buffer, out_buffer);
-    while let Ok((con, _)) = socket.accept().await {
-        match mux.send(con).await {
-            Ok(_) => {},
-            Err(e) => {
-                log::error!("{}", e);
-                return;
+    let mut mux = Multiplexer::new();
+    let closed = mux.closed();
+    (mux.consume_connections(in_buffer, out_buffer), closed)
+}
+
+// One agent listens on every port of a range
+pub async fn multiplex_con_to_stdio(ports: Vec<u16>) {
+    let mut listeners = vec![];
+    for port in ports {
+        listeners.push((port, TCPConnectionProvider::from_addresses(localhost(port)).listen_for_connections().await));
+    }
+    let (mux, closed) = stdio_multiplexer();
+    // stop when the other side shuts down or goes away
+    tokio::select! {
+        _ = accept_on_ports(listeners, mux) => {},
+        _ = closed => {}
+    }
+}
+
+// Connections are tagged with the port they were accepted on, so `rs` picks the destination of that port
+async fn accept_on_ports(listeners: Vec<(u16, TcpListeners)>, mux: Sender<(TcpStream, String)>) {
+    let accepts = listeners.into_iter().map(|(port, listener)| {
+        let mux = mux.clone();
+        async move {
+            while let Ok((con, _)) = listener.accept().await {
+                if let Err(e) = mux.send((con, port.to_string())).await {
+                    log::error!("{}", e);
+                    return;
+                }
             }
         }
+    });
+    join_all(accepts).await;
+}
+
+pub async fn multiplex_unix_to_stdio(path: String, mode: Option<u32>) {
+    let socket = UnixConnectionProvider::new(path.clone(), mode).listen_for_connections().await;
+    let (mux, closed) = stdio_multiplexer();
+    tokio::pin!(closed);
+    loop {
+        // stop when the other side shuts down or goes away
+        let con = tokio::select! {
+            con = socket.accept() => match con {
+                Ok((con, _)) => con,
+                Err(_) => break
+            },
+            _ = &mut closed => break
+        };
+        if let Err(e) = mux.send((con, String::new())).await {
+            log::error!("{}", e);
+            break;
+        }
     }
+    _ = tokio::fs::remove_file(path).await;
 }
 
-pub async fn stdio_to_con(addr: SocketAddr) {
-    let soc = TCPConnectionProvider::new(addr).connect().await;
+// Connects to destinations requested over stdio, each connection starts with a status byte
+pub async fn dial_destinations_from_stdio() {
+    let in_buffer = unsafe { File::from_raw_fd(0) }; //stdin
+    let out_buffer = unsafe { File::from_raw_fd(1) }; //stdout
+    let mut mux = Multiplexer::new();
+    let closed = mux.closed();
+    let mut cons = mux.accept_destinations(in_buffer, out_buffer);
+    tokio::pin!(closed);
+    loop {
+        let (mut con, destination) = tokio::select! {
+            con = cons.recv() => match con {
+                Some(con) => con,
+                None => return
+            },
+            _ = &mut closed => return
+        };
+        tokio::spawn(async move {
+            let soc: std::io::Result<Box<dyn PipeEndpoint>> = match destination.strip_prefix(UNIX_DESTINATION_PREFIX) {
+                Some(path) => UnixStream::connect(path).await.map(|s| Box::new(s) as Box<dyn PipeEndpoint>),
+                None => TcpStream::connect(destination.as_str()).await.map(|s| Box::new(s) as Box<dyn PipeEndpoint>),
+            };
+            match soc {
+                Ok(soc) => {
+                    if con.write_all(&[socks::SUCCEEDED]).await.is_ok() {
+                        connect(Box::new(con), soc).await;
+                    }
+                },
+                Err(e) => {
+                    log::info!("Failed to connect to {}: {}", destination, e);
+                    let status = match e.kind() {
+                        std::io::ErrorKind::ConnectionRefused => socks::CONNECTION_REFUSED,
+                        _ => socks::HOST_UNREACHABLE,
+                    };
+                    _ = con.write_all(&[status]).await;
+                }
+            }
+        });
+    }
+}
+
+pub async fn stdio_to_con(addrs: Vec<SocketAddr>) {
+    let soc = TCPConnectionProvider::from_addresses(addrs).connect().await;
+    connect(Box::new(soc), Box::new(StdioPipeEndpoint{})).await;
+    exit(0);
+}
+
+pub async fn stdio_to_unix(path: String) {
+    let soc = UnixConnectionProvider::new(path, None).connect().await;
     connect(Box::new(soc), Box::new(StdioPipeEndpoint{})).await;
     exit(0);
 }
@@ -132,3 +254,36 @@ pub async fn get_copy_source(path: String) -> Result<PipeCopySource, Error> {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::split;
+
+    #[tokio::test]
+    async fn one_multiplexer_serves_every_port_of_a_range() {
+        let (agent, rs) = tokio::io::duplex(255);
+        let (agent_in, agent_out) = split(agent);
+        let (rs_in, rs_out) = split(rs);
+        let mux = Multiplexer::new().consume_connections(agent_in, agent_out);
+        let mut cons = Multiplexer::new().accept_destinations(rs_in, rs_out);
+
+        let mut listeners = vec![];
+        for _ in 0..3 {
+            let listener = TCPConnectionProvider::new("127.0.0.1:0".parse().unwrap()).listen_for_connections().await;
+            listeners.push((listener.local_addrs()[0].port(), listener));
+        }
+        let ports: Vec<u16> = listeners.iter().map(|(port, _)| *port).collect();
+        tokio::spawn(accept_on_ports(listeners, mux));
+
+        for port in &ports {
+            let mut client = TcpStream::connect(("127.0.0.1", *port)).await.unwrap();
+            let (mut con, tag) = cons.recv().await.unwrap();
+            assert_eq!(tag, port.to_string());
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            con.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        }
+    }
+}
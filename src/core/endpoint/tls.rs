@@ -0,0 +1,137 @@
+use std::{pin::Pin, task::{Context, Poll}};
+
+use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, copy_bidirectional, duplex, split};
+use tokio_openssl::SslStream;
+
+use super::PipeEndpoint;
+
+static BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    SslError(String)
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        Error::SslError(e.to_string())
+    }
+}
+
+impl From<openssl::ssl::Error> for Error {
+    fn from(e: openssl::ssl::Error) -> Self {
+        Error::SslError(e.to_string())
+    }
+}
+
+// Terminates TLS with the certificate chain and key from PEM files
+pub fn acceptor(cert: &str, key: &str) -> Result<SslAcceptor, Error> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    builder.set_certificate_chain_file(cert)?;
+    builder.set_private_key_file(key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    Ok(builder.build())
+}
+
+// Originates TLS verifying servers with system CA certificates or the ones from the PEM file
+pub fn connector(ca: Option<&str>, insecure: bool) -> Result<SslConnector, Error> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    if let Some(ca) = ca {
+        builder.set_ca_file(ca)?;
+    }
+    if insecure {
+        builder.set_verify(SslVerifyMode::NONE);
+    }
+    Ok(builder.build())
+}
+
+enum Side {
+    Accept(SslAcceptor),
+    // without server name there is no SNI and only the certificate chain is verified
+    Connect(SslConnector, Option<String>)
+}
+
+// TLS over another endpoint, the handshake runs in the background and the endpoint
+// closes when it fails
+pub struct TlsPipeEndpoint {
+    inner: Box<dyn PipeEndpoint>,
+    side: Side
+}
+
+impl TlsPipeEndpoint {
+    pub fn accept(inner: Box<dyn PipeEndpoint>, acceptor: SslAcceptor) -> Self {
+        TlsPipeEndpoint { inner, side: Side::Accept(acceptor) }
+    }
+
+    pub fn connect(inner: Box<dyn PipeEndpoint>, connector: SslConnector, server_name: Option<String>) -> Self {
+        TlsPipeEndpoint { inner, side: Side::Connect(connector, server_name) }
+    }
+}
+
+impl PipeEndpoint for TlsPipeEndpoint {
+    fn get_sink_and_source(self: Box<Self>) -> (Box<dyn AsyncRead + Unpin + Send + 'static>, Box<dyn AsyncWrite + Unpin + Send + 'static>) {
+        let (mut plain, local) = duplex(BUFFER_SIZE);
+        let (reader, writer) = self.inner.get_sink_and_source();
+        let stream = JoinedStream { reader, writer };
+        let side = self.side;
+        tokio::spawn(async move {
+            match handshake(side, stream).await {
+                Ok(mut tls) => _ = copy_bidirectional(&mut tls, &mut plain).await,
+                Err(e) => log::error!("TLS handshake failed: {:?}", e),
+            }
+        });
+        let (reader, writer) = split(local);
+        (Box::new(reader), Box::new(writer))
+    }
+}
+
+async fn handshake(side: Side, stream: JoinedStream) -> Result<SslStream<JoinedStream>, Error> {
+    match side {
+        Side::Accept(acceptor) => {
+            let mut tls = SslStream::new(Ssl::new(acceptor.context())?, stream)?;
+            Pin::new(&mut tls).accept().await?;
+            Ok(tls)
+        },
+        Side::Connect(connector, server_name) => {
+            let mut config = connector.configure()?;
+            let ssl = match server_name {
+                Some(name) => config.into_ssl(&name)?,
+                None => {
+                    config.set_use_server_name_indication(false);
+                    config.set_verify_hostname(false);
+                    config.into_ssl("")?
+                }
+            };
+            let mut tls = SslStream::new(ssl, stream)?;
+            Pin::new(&mut tls).connect().await?;
+            Ok(tls)
+        },
+    }
+}
+
+// Source and sink of an endpoint as a single stream
+struct JoinedStream {
+    reader: Box<dyn AsyncRead + Unpin + Send + 'static>,
+    writer: Box<dyn AsyncWrite + Unpin + Send + 'static>
+}
+
+impl AsyncRead for JoinedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for JoinedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt}, time::timeout};
+
+// Both sides of the agent stdio stream start with magic, protocol version and feature flags
+static MAGIC: &[u8; 4] = b"RSAG";
+pub static PROTOCOL_VERSION: u8 = 1;
+
+// bits 0 and 1 are kept free for udp and compression
+pub static FEATURE_DYNAMIC_DESTINATIONS: u8 = 1 << 2;
+// listening agents tag connections with the port they were accepted on
+pub static FEATURE_PORT_TAGS: u8 = 1 << 3;
+
+// Features supported by this build
+pub static FEATURES: u8 = FEATURE_DYNAMIC_DESTINATIONS | FEATURE_PORT_TAGS;
+
+// Seconds to wait for the other side to answer
+static HANDSHAKE_TIMEOUT: u64 = 10;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    IoError(String),
+    NotAnAgent,
+    VersionMismatch(String),
+    MissingFeatures(String),
+    Timeout
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IoError(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u8,
+    pub features: u8
+}
+
+impl Hello {
+    pub fn local() -> Self {
+        Hello { version: PROTOCOL_VERSION, features: FEATURES }
+    }
+
+    pub fn supports(&self, features: u8) -> bool {
+        self.features & features == features
+    }
+
+    fn encode(&self) -> [u8; 6] {
+        [MAGIC[0], MAGIC[1], MAGIC[2], MAGIC[3], self.version, self.features]
+    }
+
+    fn decode(buf: &[u8; 6]) -> Result<Self, Error> {
+        if &buf[..4] != MAGIC {
+            return Err(Error::NotAnAgent);
+        }
+        Ok(Hello { version: buf[4], features: buf[5] })
+    }
+}
+
+pub fn feature_names(features: u8) -> Vec<&'static str> {
+    [(FEATURE_DYNAMIC_DESTINATIONS, "dynamic destinations"), (FEATURE_PORT_TAGS, "port tags")]
+        .into_iter()
+        .filter(|(flag, _)| features & flag != 0)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+// Sends local hello and checks the one of the other side, fails when it lacks required features
+pub async fn handshake(reader: &mut (impl AsyncRead + Unpin), writer: &mut (impl AsyncWrite + Unpin), required: u8) -> Result<Hello, Error> {
+    writer.write_all(&Hello::local().encode()).await?;
+    writer.flush().await?;
+    let mut buf = [0u8; 6];
+    timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), reader.read_exact(&mut buf)).await.or(Err(Error::Timeout))??;
+    let remote = Hello::decode(&buf)?;
+    if remote.version != PROTOCOL_VERSION {
+        return Err(Error::VersionMismatch(format!("other side speaks protocol version {}, this build {}", remote.version, PROTOCOL_VERSION)));
+    }
+    if !remote.supports(required) {
+        return Err(Error::MissingFeatures(feature_names(required & !remote.features).join(", ")));
+    }
+    Ok(remote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn handshake_between_same_builds() {
+        let (local, remote) = tokio::io::duplex(64);
+        let (mut local_in, mut local_out) = tokio::io::split(local);
+        let (mut remote_in, mut remote_out) = tokio::io::split(remote);
+        let (local_hello, remote_hello) = tokio::join!(
+            handshake(&mut local_in, &mut local_out, 0),
+            handshake(&mut remote_in, &mut remote_out, 0)
+        );
+        assert_eq!(local_hello.unwrap(), Hello::local());
+        assert_eq!(remote_hello.unwrap(), Hello::local());
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_other_streams() {
+        let mut reader: &[u8] = b"HTTP/1.1";
+        let mut writer = vec![];
+        assert!(matches!(handshake(&mut reader, &mut writer, 0).await, Err(Error::NotAnAgent)));
+
+        let mut reader: &[u8] = &[b'R', b'S', b'A', b'G', PROTOCOL_VERSION + 1, 0];
+        assert!(matches!(handshake(&mut reader, &mut writer, 0).await, Err(Error::VersionMismatch(_))));
+
+        let mut reader: &[u8] = &[b'R', b'S', b'A', b'G', PROTOCOL_VERSION, 0];
+        match handshake(&mut reader, &mut writer, FEATURE_DYNAMIC_DESTINATIONS).await {
+            Err(Error::MissingFeatures(missing)) => assert_eq!(missing, "dynamic destinations"),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -1,8 +1,8 @@
-use std::{collections::HashMap};
+use std::{collections::{HashMap, VecDeque}};
 
 use anyhow::Error;
 use futures::{StreamExt, SinkExt};
-use tokio::{io::{AsyncRead, AsyncWrite, AsyncReadExt, split, AsyncWriteExt}, sync::{mpsc, mpsc::{Sender, Receiver}, oneshot}, select};
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncReadExt, split, AsyncWriteExt}, sync::{mpsc, mpsc::{Sender, Receiver}, oneshot}, select, task::JoinHandle, io::DuplexStream};
 use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
 use bytes::{BytesMut, BufMut, Buf};
 
@@ -16,6 +16,55 @@ struct MuxFrame {
     bytes: Vec<u8>
 }
 
+impl MuxFrame {
+    // stream 0 without a connection id asks the other side to shut down
+    fn shutdown() -> Self {
+        MuxFrame { stream_id: 0, bytes: vec![] }
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.stream_id == 0 && self.bytes.is_empty()
+    }
+}
+
+// Opens connections to destinations the other side connects to
+#[derive(Clone)]
+pub struct MuxConnector {
+    requests: Sender<(String, oneshot::Sender<DuplexStream>)>
+}
+
+impl MuxConnector {
+    // Other side went away, nothing could be opened anymore
+    pub fn is_closed(&self) -> bool {
+        self.requests.is_closed()
+    }
+
+    // Destination is limited by the frame size
+    pub async fn open(&self, destination: &str) -> Option<DuplexStream> {
+        if destination.len() > 254 {
+            return None;
+        }
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.requests.send((destination.to_string(), reply_tx)).await.ok()?;
+        reply_rx.await.ok()
+    }
+}
+
+// Requests shutdown of the other side of a multiplexer
+pub struct MuxShutdown {
+    requests: Sender<oneshot::Sender<()>>
+}
+
+impl MuxShutdown {
+    // Resolves when the shutdown message was written
+    pub async fn shutdown(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.requests.send(done_tx).await.is_ok() {
+            _ = done_rx.await;
+        }
+    }
+}
+
 impl Decoder for MuxDecoder {
     type Item = MuxFrame;
     type Error = Error;
@@ -30,7 +79,7 @@ impl Decoder for MuxDecoder {
             return Ok(None);
         }
         src.advance(2);
-        let bytes = src.split().to_vec();
+        let bytes = src.split_to(usize::from(len)).to_vec();
         return Ok(Some(MuxFrame {stream_id: id, bytes: bytes}));
     }
 }
@@ -51,15 +100,40 @@ impl Encoder<MuxFrame> for MuxEncoder {
 pub struct Multiplexer {
     connection_numbers: [bool; 255],
     connections: HashMap<u8, Sender<MuxFrame>>,
+    shutdown_tx: Sender<oneshot::Sender<()>>,
+    shutdown_rx: Receiver<oneshot::Sender<()>>,
+    closed_tx: Option<oneshot::Sender<()>>,
 }
 
 
 impl Multiplexer {
 
     pub fn new() -> Self {
+        let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
         Multiplexer { 
             connection_numbers: [false; 255], 
             connections: HashMap::new(),
+            shutdown_tx,
+            shutdown_rx,
+            closed_tx: None,
+        }
+    }
+
+    // Handle sending shutdown message to the other side
+    pub fn shutdown_handle(&self) -> MuxShutdown {
+        MuxShutdown { requests: self.shutdown_tx.clone() }
+    }
+
+    // Resolves when the other side requested shutdown or closed its stream
+    pub fn closed(&mut self) -> oneshot::Receiver<()> {
+        let (closed_tx, closed_rx) = oneshot::channel();
+        self.closed_tx = Some(closed_tx);
+        closed_rx
+    }
+
+    fn close(&mut self) {
+        if let Some(closed_tx) = self.closed_tx.take() {
+            _ = closed_tx.send(());
         }
     }
 
@@ -77,13 +151,25 @@ impl Multiplexer {
         self.connection_numbers[usize::from(id)] = false;
     }
 
+    // Connections waiting for a free id get the ids of closed ones, oldest first
+    fn reserve_pending<T>(&mut self, pending: &mut VecDeque<T>) -> Vec<(u8, T)> {
+        let mut ready = vec![];
+        while !pending.is_empty() {
+            match self.reserve_id() {
+                Some(id) => ready.push((id, pending.pop_front().unwrap())),
+                None => break,
+            }
+        }
+        ready
+    }
+
     // from connection to out_buffer
-    fn pass_outgoing(&mut self, frame_sink: Sender<MuxFrame>, mut stream: impl AsyncRead + Unpin + Send + 'static, id: u8, kill_chan: oneshot::Sender<bool>, kill_sig: oneshot::Receiver<bool>) {
+    fn pass_outgoing(&mut self, frame_sink: Sender<MuxFrame>, mut stream: impl AsyncRead + Unpin + Send + 'static, id: u8, open: Vec<u8>, kill_chan: oneshot::Sender<bool>, kill_sig: oneshot::Receiver<bool>) {
         
         tokio::spawn(async move {
             let mut buf = [0; 255];
             // stream with id 0 means its a new connection
-            if let Err(_) = frame_sink.send(MuxFrame{stream_id: 0, bytes: vec![id]}).await {
+            if let Err(_) = frame_sink.send(MuxFrame{stream_id: 0, bytes: open}).await {
                 return;
             }
             tokio::pin!(kill_sig); 
@@ -99,7 +185,7 @@ impl Multiplexer {
                     break;
                 }
                 let frame = MuxFrame{stream_id: id, bytes: (&buf[..len]).to_vec() };
-                log::info!("({})->: {:?}", frame.stream_id, unsafe{std::str::from_utf8_unchecked(&frame.bytes)});
+                log::debug!("({})->: {} bytes", frame.stream_id, frame.bytes.len());
                 if let Err(_) = frame_sink.send(frame).await {
                     return;
                 }
@@ -133,18 +219,18 @@ impl Multiplexer {
                 if let Err(_) = sink.write_all(&frame.bytes).await {
                     break;
                 }
-                log::info!("({})<-: {:?}", frame.stream_id, unsafe{std::str::from_utf8_unchecked(&frame.bytes)});
+                log::debug!("({})<-: {} bytes", frame.stream_id, frame.bytes.len());
             }
             _ = kill_chan.send(true);
         });
     }
     // forwards frames to in_buffer and returns frames from out_buffer
-    fn pipe_frames(&self, in_buffer: impl AsyncRead + Unpin + Send + 'static, out_buffer: impl AsyncWrite + Unpin + Send + 'static, mut chan: Receiver<MuxFrame>) -> Receiver<MuxFrame> {
+    fn pipe_frames(&self, in_buffer: impl AsyncRead + Unpin + Send + 'static, out_buffer: impl AsyncWrite + Unpin + Send + 'static, mut chan: Receiver<MuxFrame>) -> (Receiver<MuxFrame>, JoinHandle<()>) {
        
         let (con_tx, con_rx) = mpsc::channel::<MuxFrame>(1);
 
         // process outgoing data
-        tokio::spawn(async move {
+        let writer = tokio::spawn(async move {
             let encoder = MuxEncoder{};
             let mut out = FramedWrite::new(out_buffer, encoder);
             while let Some(frame) = chan.recv().await {
@@ -164,34 +250,30 @@ impl Multiplexer {
                 }
             }
         });
-        return con_rx;
+        return (con_rx, writer);
     }
 
-    fn accept_connection(&mut self, frames: Sender<MuxFrame>, soc: impl AsyncRead + AsyncWrite + Unpin + Send + 'static) {
+    // open frame carries the tag of the connection after its id, e.g. the port it was accepted on
+    fn accept_connection(&mut self, frames: Sender<MuxFrame>, id: u8, soc: impl AsyncRead + AsyncWrite + Unpin + Send + 'static, tag: &str) {
         let (con_tx, frame_stream) = mpsc::channel::<MuxFrame>(1);
         let (stream, sink) = split(soc);
-        let id = match self.reserve_id() {
-            Some(id) => id,
-            None => {
-                log::error!("Reached maximum of 254 connections");
-                return;
-            }
-        };
         let (kill_in, end_in) = oneshot::channel::<bool>();
         let (kill_out, end_out) = oneshot::channel::<bool>();
-        self.pass_outgoing(frames, stream, id, kill_in, end_out);
+        self.pass_outgoing(frames, stream, id, [&[id], tag.as_bytes()].concat(), kill_in, end_out);
         self.connections.insert(id, con_tx);
         self.pass_incoming( frame_stream, sink, kill_out, end_in);
     }
 
-    fn create_connection(&mut self, frames: Sender<MuxFrame>, id: u8) -> impl AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    // open frame carries destination of the connection after its id when it is chosen by this side
+    fn create_connection(&mut self, frames: Sender<MuxFrame>, id: u8, destination: &str) -> DuplexStream {
         let (con_tx, con_rx) = mpsc::channel::<MuxFrame>(1);
         
         let (soc_in, soc_out) = tokio::io::duplex(253);
         let (stream, sink) = split(soc_in);
         let (kill_in, end_in) = oneshot::channel::<bool>();
         let (kill_out, end_out) = oneshot::channel::<bool>();
-        self.pass_outgoing(frames, stream, id, kill_in, end_out);
+        let open = [&[id], destination.as_bytes()].concat();
+        self.pass_outgoing(frames, stream, id, open, kill_in, end_out);
         self.connections.insert(id, con_tx);
         self.pass_incoming( con_rx, sink, kill_out, end_in);
         return soc_out;
@@ -206,30 +288,52 @@ impl Multiplexer {
         self.release_id(id);
     }
 
-    pub fn consume_connections<T>(mut self, in_buffer: impl AsyncRead + Unpin + Send + 'static, out_buffer: impl AsyncWrite + Unpin + Send + 'static) -> Sender<T>
+    // Multiplexes connections sent with their tag, the other side gets both from accept_destinations
+    pub fn consume_connections<T>(mut self, in_buffer: impl AsyncRead + Unpin + Send + 'static, out_buffer: impl AsyncWrite + Unpin + Send + 'static) -> Sender<(T, String)>
         where T: AsyncRead + AsyncWrite + Unpin + Send + 'static
     {
-        let (con_tx, mut con_rx) = mpsc::channel::<T>(1);
+        let (con_tx, mut con_rx) = mpsc::channel::<(T, String)>(1);
         let (frame_tx, frame_rx) = mpsc::channel::<MuxFrame>(1);
         let (out_frame_tx, mut out_frames) = mpsc::channel::<MuxFrame>(1);
-        let mut in_frames = self.pipe_frames(in_buffer, out_buffer, frame_rx);
+        let (mut in_frames, _) = self.pipe_frames(in_buffer, out_buffer, frame_rx);
         tokio::spawn(async move {
+            // connections beyond 254 at a time wait until one closes
+            let mut pending = VecDeque::new();
             loop {
                 tokio::select! {
                     // open new connections
-                    Some(soc) = con_rx.recv() => self.accept_connection(out_frame_tx.clone(), soc),
+                    Some(soc) = con_rx.recv() => {
+                        match self.reserve_id() {
+                            Some(id) => self.accept_connection(out_frame_tx.clone(), id, soc.0, &soc.1),
+                            None => {
+                                log::info!("All 254 connections in use, waiting for one to close");
+                                pending.push_back(soc);
+                            }
+                        }
+                    },
                     
                     // from out_buffer to connection
                     Some(frame) = out_frames.recv() => {
                         if frame.stream_id == 255 {
                             self.release_connection(frame.bytes[0]);
+                            for (id, (soc, tag)) in self.reserve_pending(&mut pending) {
+                                self.accept_connection(out_frame_tx.clone(), id, soc, &tag);
+                            }
                         } 
                         frame_tx.send(frame).await.unwrap();
                         
                         
                     },
                     // from in_buffer to connection
-                    Some(frame) = in_frames.recv() => {
+                    frame_res = in_frames.recv() => {
+                        let frame = match frame_res {
+                            Some(frame) if !frame.is_shutdown() => frame,
+                            // other side asked to shut down or went away
+                            _ => {
+                                self.close();
+                                break;
+                            }
+                        };
                         let frame_id = frame.stream_id;
                         let stream_id = match frame.stream_id {
                             255 => frame.bytes[0],
@@ -243,6 +347,9 @@ impl Multiplexer {
                         }
                         if frame_id == 255 { 
                             self.release_connection(stream_id);
+                            for (id, (soc, tag)) in self.reserve_pending(&mut pending) {
+                                self.accept_connection(out_frame_tx.clone(), id, soc, &tag);
+                            }
                         } 
                         
                     },
@@ -255,15 +362,23 @@ impl Multiplexer {
         return con_tx;
     }
 
-    pub fn produce_connections(mut self, in_buffer: impl AsyncRead + Unpin + Send + 'static, out_buffer: impl AsyncWrite + Unpin + Send + 'static) -> Receiver<impl AsyncRead + AsyncWrite + Unpin + Send + 'static>
+    // Connections opened by the other side together with the destinations it asked for or their tags
+    pub fn accept_destinations(mut self, in_buffer: impl AsyncRead + Unpin + Send + 'static, out_buffer: impl AsyncWrite + Unpin + Send + 'static) -> Receiver<(DuplexStream, String)>
     {
         let (con_tx, con_rx) = mpsc::channel(1);
         let (out_frames_proxy_tx, out_frames_proxy) = mpsc::channel::<MuxFrame>(1);
         let (out_frame_tx, mut out_frames) = mpsc::channel::<MuxFrame>(1);
-        let mut in_frames = self.pipe_frames(in_buffer, out_buffer, out_frames_proxy);
+        let (mut in_frames, writer) = self.pipe_frames(in_buffer, out_buffer, out_frames_proxy);
         tokio::spawn(async move {
+            let mut shutdown_done = None;
             loop {
                 tokio::select! {
+                    // ask the other side to shut down
+                    Some(done) = self.shutdown_rx.recv() => {
+                        _ = out_frames_proxy_tx.send(MuxFrame::shutdown()).await;
+                        shutdown_done = Some(done);
+                        break;
+                    },
                     // from out_buffer to connection
                     frame_res = out_frames.recv() => {
                         match frame_res {
@@ -285,11 +400,16 @@ impl Multiplexer {
                         match frame_res {
                             Some(frame) => {
                                 let frame_id = frame.stream_id;
+                                if frame.is_shutdown() {
+                                    self.close();
+                                    break;
+                                }
                                 let stream_id = match frame.stream_id {
                                     255 => frame.bytes[0],
                                     0 => {
-                                        let con = self.create_connection(out_frame_tx.clone(), frame.bytes[0]);
-                                        if let Err(e) = con_tx.send(con).await {
+                                        let destination = String::from_utf8_lossy(&frame.bytes[1..]).to_string();
+                                        let con = self.create_connection(out_frame_tx.clone(), frame.bytes[0], "");
+                                        if let Err(e) = con_tx.send((con, destination)).await {
                                             log::error!("{}", e);
                                         }
                                         continue;
@@ -310,6 +430,7 @@ impl Multiplexer {
                                 }
                             },
                             None => {
+                                self.close();
                                 break;
                             }
                         }
@@ -317,10 +438,192 @@ impl Multiplexer {
                     else => { break } 
                 }
             }
+            // wait until pending frames are written
+            drop(out_frames_proxy_tx);
+            _ = writer.await;
+            if let Some(done) = shutdown_done {
+                _ = done.send(());
+            }
+        });
+
+        con_rx
+    }
+
+    // Opens connections to destinations chosen by this side, the other side accepts them with accept_destinations
+    pub fn open_connections(mut self, in_buffer: impl AsyncRead + Unpin + Send + 'static, out_buffer: impl AsyncWrite + Unpin + Send + 'static) -> MuxConnector
+    {
+        let (request_tx, mut requests) = mpsc::channel::<(String, oneshot::Sender<DuplexStream>)>(1);
+        let (out_frames_proxy_tx, out_frames_proxy) = mpsc::channel::<MuxFrame>(1);
+        let (out_frame_tx, mut out_frames) = mpsc::channel::<MuxFrame>(1);
+        let (mut in_frames, writer) = self.pipe_frames(in_buffer, out_buffer, out_frames_proxy);
+        tokio::spawn(async move {
+            let mut shutdown_done = None;
+            // opens beyond 254 connections at a time wait until one closes
+            let mut pending = VecDeque::new();
+            loop {
+                tokio::select! {
+                    // ask the other side to shut down
+                    Some(done) = self.shutdown_rx.recv() => {
+                        _ = out_frames_proxy_tx.send(MuxFrame::shutdown()).await;
+                        shutdown_done = Some(done);
+                        break;
+                    },
+                    // open new connections
+                    Some((destination, reply)) = requests.recv() => {
+                        match self.reserve_id() {
+                            Some(id) => _ = reply.send(self.create_connection(out_frame_tx.clone(), id, &destination)),
+                            None => {
+                                log::info!("All 254 connections in use, waiting for one to close");
+                                pending.push_back((destination, reply));
+                            }
+                        }
+                    },
+                    // from out_buffer to connection
+                    Some(frame) = out_frames.recv() => {
+                        if frame.stream_id == 255 {
+                            self.release_connection(frame.bytes[0]);
+                            self.open_pending(&out_frame_tx, &mut pending);
+                        }
+                        if out_frames_proxy_tx.send(frame).await.is_err() {
+                            break;
+                        }
+                    },
+                    // from in_buffer to connection
+                    frame_res = in_frames.recv() => {
+                        let frame = match frame_res {
+                            Some(frame) => frame,
+                            None => {
+                                self.close();
+                                break;
+                            }
+                        };
+                        // the other side confirms connections this side opened
+                        if frame.stream_id == 0 {
+                            continue;
+                        }
+                        let stream_id = match frame.stream_id {
+                            255 => frame.bytes[0],
+                            _ => frame.stream_id
+                        };
+                        let frame_id = frame.stream_id;
+                        if let Some(con) = self.connections.get(&stream_id) {
+                            _ = con.send(frame).await;
+                        }
+                        if frame_id == 255 {
+                            self.release_connection(stream_id);
+                            self.open_pending(&out_frame_tx, &mut pending);
+                        }
+                    },
+                    else => { break }
+                }
+            }
+            // wait until pending frames are written
+            drop(out_frames_proxy_tx);
+            _ = writer.await;
+            if let Some(done) = shutdown_done {
+                _ = done.send(());
+            }
         });
 
-        return con_rx;
+        MuxConnector { requests: request_tx }
+    }
+
+    // requests whose caller gave up meanwhile are dropped instead of opened
+    fn open_pending(&mut self, frames: &Sender<MuxFrame>, pending: &mut VecDeque<(String, oneshot::Sender<DuplexStream>)>) {
+        pending.retain(|(_, reply)| !reply.is_closed());
+        for (id, (destination, reply)) in self.reserve_pending(pending) {
+            _ = reply.send(self.create_connection(frames.clone(), id, &destination));
+        }
+    }
+
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoder_splits_consecutive_frames() {
+        let mut buf = BytesMut::from(&[1u8, 2, b'a', b'b', 0, 0][..]);
+        let frame = MuxDecoder{}.decode(&mut buf).unwrap().unwrap();
+        assert_eq!((frame.stream_id, frame.bytes), (1, b"ab".to_vec()));
+        let frame = MuxDecoder{}.decode(&mut buf).unwrap().unwrap();
+        assert!(frame.is_shutdown());
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_other_side() {
+        let (local, remote) = tokio::io::duplex(255);
+        let (local_in, local_out) = split(local);
+        let (remote_in, remote_out) = split(remote);
+
+        let producer = Multiplexer::new();
+        let shutdown = producer.shutdown_handle();
+        let _cons = producer.accept_destinations(local_in, local_out);
+
+        let mut consumer = Multiplexer::new();
+        let closed = consumer.closed();
+        let _cons = consumer.consume_connections::<tokio::io::DuplexStream>(remote_in, remote_out);
+
+        shutdown.shutdown().await;
+        closed.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn opened_connections_carry_destination() {
+        let (local, remote) = tokio::io::duplex(255);
+        let (local_in, local_out) = split(local);
+        let (remote_in, remote_out) = split(remote);
+
+        let connector = Multiplexer::new().open_connections(local_in, local_out);
+        let mut cons = Multiplexer::new().accept_destinations(remote_in, remote_out);
+
+        let mut opened = connector.open("db.default.svc:5432").await.unwrap();
+        let (mut accepted, destination) = cons.recv().await.unwrap();
+        assert_eq!(destination, "db.default.svc:5432");
+
+        opened.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        accepted.write_all(b"pong").await.unwrap();
+        opened.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
     }
 
+    #[tokio::test]
+    async fn opens_beyond_254_connections_wait_for_one_to_close() {
+        let (local, remote) = tokio::io::duplex(255);
+        let (local_in, local_out) = split(local);
+        let (remote_in, remote_out) = split(remote);
 
+        let connector = Multiplexer::new().open_connections(local_in, local_out);
+        let mut cons = Multiplexer::new().accept_destinations(remote_in, remote_out);
+        let accepted = tokio::spawn(async move {
+            let mut accepted = vec![];
+            while let Some((con, _)) = cons.recv().await {
+                accepted.push(con);
+            }
+            accepted
+        });
+
+        let mut opened = vec![];
+        for _ in 0..254 {
+            opened.push(connector.open("db:5432").await.unwrap());
+        }
+        let waiting = tokio::spawn({
+            let connector = connector.clone();
+            async move { connector.open("db:5432").await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+
+        drop(opened.pop());
+        let con = tokio::time::timeout(std::time::Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        assert!(con.is_some());
+        drop((opened, connector));
+        accepted.abort();
+    }
 }
@@ -0,0 +1,182 @@
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex}, time::Duration};
+
+use futures::future::{BoxFuture, AbortHandle, Abortable};
+use tokio::{signal::unix::{signal, SignalKind}, sync::watch, time::timeout};
+
+// Seconds open connections are given to finish once shutdown starts, the rest is closed
+static DRAIN_TIMEOUT: u64 = 5;
+// Seconds a single cleanup may take
+static CLEANUP_TIMEOUT: u64 = 10;
+
+struct Cleanup {
+    description: String,
+    task: BoxFuture<'static, Result<(), String>>
+}
+
+struct Inner {
+    started: watch::Sender<bool>,
+    connections: watch::Sender<usize>,
+    open: Mutex<(u64, HashMap<u64, AbortHandle>)>,
+    cleanups: Mutex<Vec<Cleanup>>
+}
+
+// Coordinates the end of a session: stops accepting connections, drains open ones and
+// cleans up agents, files, pods and containers created for it
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>
+}
+
+// Open connection, shutdown waits for it to finish
+struct ConnectionGuard {
+    inner: Arc<Inner>,
+    id: u64
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.inner.open.lock().unwrap().1.remove(&self.id);
+        self.inner.connections.send_modify(|c| *c -= 1);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (started, _) = watch::channel(false);
+        let (connections, _) = watch::channel(0);
+        Shutdown { inner: Arc::new(Inner { started, connections, open: Mutex::new((0, HashMap::new())), cleanups: Mutex::new(vec![]) }) }
+    }
+
+    // Registers cleanup ran on shutdown, cleanups run in reverse order of registration
+    pub fn register(&self, description: String, task: impl Future<Output = Result<(), String>> + Send + 'static) {
+        self.inner.cleanups.lock().unwrap().push(Cleanup { description, task: Box::pin(task) });
+    }
+
+    // Runs connection in the background, shutdown waits for it to finish and closes it after the drain timeout
+    pub fn spawn_connection(&self, connection: impl Future<Output = ()> + Send + 'static) {
+        let (handle, registration) = AbortHandle::new_pair();
+        let id = {
+            let mut open = self.inner.open.lock().unwrap();
+            let (last, handles) = &mut *open;
+            *last += 1;
+            handles.insert(*last, handle);
+            *last
+        };
+        self.inner.connections.send_modify(|c| *c += 1);
+        let guard = ConnectionGuard { inner: self.inner.clone(), id };
+        tokio::spawn(async move {
+            _ = Abortable::new(connection, registration).await;
+            drop(guard);
+        });
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.inner.started.borrow()
+    }
+
+    // Resolves once shutdown started
+    pub async fn started(&self) {
+        let mut started = self.inner.started.subscribe();
+        while !*started.borrow_and_update() {
+            if started.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Resolves with the name of the first SIGINT, SIGTERM or SIGHUP received
+    pub async fn signal() -> &'static str {
+        let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+        tokio::select! {
+            _ = interrupt.recv() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+            _ = hangup.recv() => "SIGHUP",
+        }
+    }
+
+    // Runs the session until it ends or a signal is received and shuts down either way
+    pub async fn run_until_signal(&self, session: impl Future<Output = Result<(), String>>) -> Result<(), String> {
+        let (reason, res) = tokio::select! {
+            res = session => (if res.is_ok() { "session ended" } else { "error" }, res),
+            signal = Shutdown::signal() => (signal, Ok(())),
+        };
+        self.shutdown(reason).await;
+        res
+    }
+
+    // Drains open connections and runs registered cleanups, reporting them to stderr
+    pub async fn shutdown(&self, reason: &str) {
+        if self.inner.started.send_replace(true) {
+            return;
+        }
+        let cleanups: Vec<Cleanup> = self.inner.cleanups.lock().unwrap().drain(..).rev().collect();
+        let mut connections = self.inner.connections.subscribe();
+        if *connections.borrow() > 0 || !cleanups.is_empty() {
+            eprintln!("Shutting down: {}", reason);
+        }
+        let drained = timeout(Duration::from_secs(DRAIN_TIMEOUT), async {
+            while *connections.borrow_and_update() > 0 {
+                if connections.changed().await.is_err() {
+                    return;
+                }
+            }
+        }).await;
+        if drained.is_err() {
+            let open: Vec<AbortHandle> = self.inner.open.lock().unwrap().1.drain().map(|(_, handle)| handle).collect();
+            open.iter().for_each(AbortHandle::abort);
+            eprintln!("Closed {} open connections", open.len());
+        }
+        for cleanup in cleanups {
+            match timeout(Duration::from_secs(CLEANUP_TIMEOUT), cleanup.task).await {
+                Ok(Ok(_)) => eprintln!("Cleaned up {}", cleanup.description),
+                Ok(Err(e)) => eprintln!("Failed to clean up {}: {}", cleanup.description, e),
+                Err(_) => eprintln!("Timed out cleaning up {}", cleanup.description),
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cleanups_run_once_in_reverse_order() {
+        let shutdown = Shutdown::new();
+        let order = Arc::new(Mutex::new(vec![]));
+        for i in 0..3 {
+            let order = order.clone();
+            shutdown.register(format!("cleanup {}", i), async move {
+                order.lock().unwrap().push(i);
+                Ok(())
+            });
+        }
+        shutdown.spawn_connection(tokio::time::sleep(Duration::from_millis(10)));
+        shutdown.shutdown("test").await;
+        shutdown.shutdown("test").await;
+        assert!(shutdown.is_started());
+        shutdown.started().await;
+        assert_eq!(*order.lock().unwrap(), vec![2, 1, 0]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn connections_are_closed_after_drain_timeout() {
+        let shutdown = Shutdown::new();
+        let (closed_tx, closed_rx) = tokio::sync::oneshot::channel::<()>();
+        shutdown.spawn_connection(async move {
+            let _closed_tx = closed_tx;
+            futures::future::pending::<()>().await;
+        });
+        shutdown.shutdown("test").await;
+        // the connection future is dropped once aborted
+        assert!(timeout(Duration::from_secs(1), closed_rx).await.is_ok());
+    }
+}
@@ -1,6 +1,6 @@
 use clap::{Parser};
 use core::cli::complete::{Complete, print_completions};
-use core::cli::{Cli, Commands, ls, pf, agent, cp};
+use core::cli::{Cli, Commands, PfArgs, ls, pf, agent, cp, socks, proxy, services};
 use core::endpoint::{docker::DockerEndpoint, kube::KubeConfigs};
 use env_logger::Builder;
 
@@ -35,16 +35,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let ls = ls::Ls::new(kube, docker);
             ls.exec(endpoint).await
         },
-        Some(Commands::Pf { origin, dst }) => {
-            let pf = pf::Pf::new(kube);
+        Some(Commands::Pf(args)) => {
+            let PfArgs { origin, dst, relay, ephemeral, sidecar, agent, socket, tls, traffic, limits, output } = *args;
+            let pf = pf::Pf::new(kube, relay, ephemeral, sidecar, agent, socket, tls, traffic, limits, output);
             pf.exec(origin, dst).await
         },
-        Some(Commands::Cp { src, dst }) => {
+        Some(Commands::Socks { listen, target, relay, ephemeral, sidecar, agent }) => {
+            let socks = socks::Socks::new(kube, relay, ephemeral, sidecar, agent);
+            socks.exec(listen, target).await
+        },
+        Some(Commands::Proxy { listen, target, relay, ephemeral, sidecar, agent }) => {
+            let proxy = proxy::Proxy::new(kube, relay, ephemeral, sidecar, agent);
+            proxy.exec(listen, target).await
+        },
+        Some(Commands::Services { target, services, dns, hosts, relay, ephemeral, sidecar, agent }) => {
+            let svc = services::Services::new(kube, relay, ephemeral, sidecar, agent);
+            svc.exec(target, services, dns, hosts).await
+        },
+        Some(Commands::Cp { src, dst, limit }) => {
             let cp = cp::Cp::new(kube, docker);
-            cp.exec(src, dst).await
+            cp.exec(src, dst, limit).await
         },
-        Some(Commands::Agent { listen, port }) => {
-            let agent = agent::Agent::new(port, listen);
+        Some(Commands::Agent { listen, port, host, dynamic, unix, socket }) => {
+            let agent = agent::Agent::new(port, listen, host, dynamic, unix, socket.socket_mode);
             agent.exec().await;
             Ok(())
         },